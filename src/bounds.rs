@@ -0,0 +1,191 @@
+use crate::matrix_calc::Matrix4f;
+use crate::vector_calc::{Vector3, Vector4f};
+
+// Axis-aligned box. An empty box has min > max and contains nothing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vector3,
+    pub radius: f32,
+}
+
+// n . p + d >= 0 on the inside.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plane {
+    pub n: Vector3,
+    pub d: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Default for Aabb {
+    fn default() -> Aabb {
+        Aabb::empty()
+    }
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Vector3::new(-f32::MAX, -f32::MAX, -f32::MAX),
+        }
+    }
+
+    pub fn from_points(points: &[Vector3]) -> Aabb {
+        let mut ret = Aabb::empty();
+        for p in points.iter() {
+            ret.expand(*p);
+        }
+        ret
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn expand(&mut self, p: Vector3) {
+        self.min = Vector3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Vector3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    pub fn merge(&self, b: Aabb) -> Aabb {
+        let mut ret = *self;
+        if !b.is_empty() {
+            ret.expand(b.min);
+            ret.expand(b.max);
+        }
+        ret
+    }
+
+    pub fn center(&self) -> Vector3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extents(&self) -> Vector3 {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn corners(&self) -> [Vector3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            Vector3::new(a.x, a.y, a.z), Vector3::new(b.x, a.y, a.z),
+            Vector3::new(a.x, b.y, a.z), Vector3::new(b.x, b.y, a.z),
+            Vector3::new(a.x, a.y, b.z), Vector3::new(b.x, a.y, b.z),
+            Vector3::new(a.x, b.y, b.z), Vector3::new(b.x, b.y, b.z),
+        ]
+    }
+
+    // Box around the eight transformed corners of an affine transform.
+    pub fn transformed(&self, m: Matrix4f) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let mut ret = Aabb::empty();
+        for c in self.corners().iter() {
+            ret.expand((c.to_point() * m).xyz());
+        }
+        ret
+    }
+}
+
+impl BoundingSphere {
+    // Centred on the box of the points, so never worse than the box itself.
+    pub fn from_points(points: &[Vector3]) -> BoundingSphere {
+        let center = Aabb::from_points(points).center();
+        let mut radius: f32 = 0.0;
+        for p in points.iter() {
+            radius = radius.max((*p - center).length());
+        }
+        BoundingSphere { center, radius }
+    }
+}
+
+impl Plane {
+    pub fn distance(&self, p: Vector3) -> f32 {
+        self.n.dot(p) + self.d
+    }
+
+    fn from_coefficients(a: f32, b: f32, c: f32, d: f32) -> Plane {
+        let len = (a * a + b * b + c * c).sqrt();
+        if len == 0.0 {
+            return Plane { n: Vector3::new(0.0, 0.0, 0.0), d };
+        }
+        Plane { n: Vector3::new(a / len, b / len, c / len), d: d / len }
+    }
+}
+
+impl Frustum {
+    // Planes of the clip volume check_cvv tests (-w <= x, y <= w, 0 <= z <= w),
+    // expressed in whatever space m maps from. Passing Transform::transform
+    // gives object-space planes, so mesh bounds need no transforming.
+    pub fn from_matrix(m: Matrix4f) -> Frustum {
+        let col = |j: usize| Vector4f { x: m.m[0][j], y: m.m[1][j], z: m.m[2][j], w: m.m[3][j] };
+        let (c0, c1, c2, c3) = (col(0), col(1), col(2), col(3));
+        let p = |v: Vector4f| Plane::from_coefficients(v.x, v.y, v.z, v.w);
+        Frustum {
+            planes: [
+                p(c3 + c0), // left
+                p(c3 - c0), // right
+                p(c3 + c1), // bottom
+                p(c3 - c1), // top
+                p(c2),      // near
+                p(c3 - c2), // far
+            ],
+        }
+    }
+
+    pub fn intersects_sphere(&self, s: &BoundingSphere) -> bool {
+        for plane in self.planes.iter() {
+            if plane.distance(s.center) < -s.radius {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Conservative: may keep boxes that only straddle two planes outside a corner.
+    pub fn intersects_aabb(&self, b: &Aabb) -> bool {
+        for plane in self.planes.iter() {
+            let p = Vector3::new(
+                match plane.n.x >= 0.0 { true => { b.max.x } false => { b.min.x } },
+                match plane.n.y >= 0.0 { true => { b.max.y } false => { b.min.y } },
+                match plane.n.z >= 0.0 { true => { b.max.z } false => { b.min.z } },
+            );
+            if plane.distance(p) < 0.0 {
+                return false;
+            }
+        }
+        true
+    }
+    // Whole box inside every plane.
+    pub fn contains_aabb(&self, b: &Aabb) -> bool {
+        for plane in self.planes.iter() {
+            let p = Vector3::new(
+                match plane.n.x >= 0.0 { true => { b.min.x } false => { b.max.x } },
+                match plane.n.y >= 0.0 { true => { b.min.y } false => { b.max.y } },
+                match plane.n.z >= 0.0 { true => { b.min.z } false => { b.max.z } },
+            );
+            if plane.distance(p) < 0.0 {
+                return false;
+            }
+        }
+        true
+    }
+}
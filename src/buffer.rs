@@ -0,0 +1,183 @@
+// Row-major 2D buffer in a single allocation, addressed as (x, y) with x
+// running along a row. Used for the color, depth and shadow buffers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Buffer2D<T> {
+    pub width: usize,
+    pub height: usize,
+    data: Vec<T>,
+}
+
+impl<T: Copy> Buffer2D<T> {
+    pub fn new(width: usize, height: usize, value: T) -> Buffer2D<T> {
+        Buffer2D {
+            width,
+            height,
+            data: vec![value; width * height],
+        }
+    }
+
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+    }
+
+    pub fn index(&self, x: usize, y: usize) -> usize {
+        debug_assert!(x < self.width && y < self.height);
+        y * self.width + x
+    }
+
+    // Checked access: None / false outside the buffer.
+    pub fn get(&self, x: usize, y: usize) -> Option<T> {
+        match x < self.width && y < self.height {
+            true => { Some(self.data[y * self.width + x]) }
+            false => { None }
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: T) -> bool {
+        match x < self.width && y < self.height {
+            true => {
+                self.data[y * self.width + x] = value;
+                true
+            }
+            false => { false }
+        }
+    }
+
+    // Unchecked access for inner loops that have already clipped to the
+    // buffer; only debug builds verify the coordinates.
+    pub unsafe fn get_unchecked(&self, x: usize, y: usize) -> T {
+        debug_assert!(x < self.width && y < self.height);
+        *self.data.get_unchecked(y * self.width + x)
+    }
+
+    pub unsafe fn set_unchecked(&mut self, x: usize, y: usize, value: T) {
+        debug_assert!(x < self.width && y < self.height);
+        *self.data.get_unchecked_mut(y * self.width + x) = value;
+    }
+
+    pub fn fill(&mut self, value: T) {
+        for v in self.data.iter_mut() {
+            *v = value;
+        }
+    }
+
+    // Reallocates only when the size actually changes.
+    pub fn resize(&mut self, width: usize, height: usize, value: T) {
+        if width == self.width && height == self.height {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        self.data.clear();
+        self.data.resize(width * height, value);
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        &mut self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    // Sub-rectangle views, clipped to the buffer.
+    pub fn view(&self, x: usize, y: usize, width: usize, height: usize) -> BufferView<'_, T> {
+        let (x, y, width, height) = self.clip(x, y, width, height);
+        BufferView { buf: self, x, y, width, height }
+    }
+
+    pub fn view_mut(&mut self, x: usize, y: usize, width: usize, height: usize) -> BufferViewMut<'_, T> {
+        let (x, y, width, height) = self.clip(x, y, width, height);
+        BufferViewMut { buf: self, x, y, width, height }
+    }
+
+    fn clip(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize, usize, usize) {
+        let x = x.min(self.width);
+        let y = y.min(self.height);
+        (x, y, width.min(self.width - x), height.min(self.height - y))
+    }
+}
+
+impl<T> std::ops::Index<(usize, usize)> for Buffer2D<T> {
+    type Output = T;
+    fn index(&self, (x, y): (usize, usize)) -> &T {
+        assert!(x < self.width, "x {} out of range for width {}", x, self.width);
+        &self.data[y * self.width + x]
+    }
+}
+
+impl<T> std::ops::IndexMut<(usize, usize)> for Buffer2D<T> {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut T {
+        assert!(x < self.width, "x {} out of range for width {}", x, self.width);
+        &mut self.data[y * self.width + x]
+    }
+}
+
+// Read-only window into a buffer; coordinates are relative to its corner.
+pub struct BufferView<'a, T> {
+    buf: &'a Buffer2D<T>,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl<'a, T: Copy> BufferView<'a, T> {
+    pub fn get(&self, x: usize, y: usize) -> Option<T> {
+        match x < self.width && y < self.height {
+            true => { self.buf.get(self.x + x, self.y + y) }
+            false => { None }
+        }
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        let start = (self.y + y) * self.buf.width + self.x;
+        &self.buf.as_slice()[start..start + self.width]
+    }
+}
+
+pub struct BufferViewMut<'a, T> {
+    buf: &'a mut Buffer2D<T>,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl<'a, T: Copy> BufferViewMut<'a, T> {
+    pub fn get(&self, x: usize, y: usize) -> Option<T> {
+        match x < self.width && y < self.height {
+            true => { self.buf.get(self.x + x, self.y + y) }
+            false => { None }
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: T) -> bool {
+        match x < self.width && y < self.height {
+            true => { self.buf.set(self.x + x, self.y + y, value) }
+            false => { false }
+        }
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        let start = (self.y + y) * self.buf.width + self.x;
+        let width = self.width;
+        &mut self.buf.as_mut_slice()[start..start + width]
+    }
+
+    pub fn fill(&mut self, value: T) {
+        for y in 0..self.height {
+            for v in self.row_mut(y).iter_mut() {
+                *v = value;
+            }
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use crate::bounds::{Aabb, Frustum};
+use crate::mesh::Mesh;
+use crate::ray::{ray_aabb, ray_triangle, Ray, RayHit};
+use crate::vector_calc::Vector3;
+
+const LEAF_SIZE: usize = 4;
+const SAH_BINS: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BvhSplit {
+    Median, // half the triangles each side, along the longest axis
+    Sah,    // binned surface area heuristic, slower to build, faster to query
+}
+
+// Nodes are stored depth first: an interior node's left child follows it
+// directly and `first` is the right child. A leaf owns `count` entries of
+// Bvh::triangles starting at `first`.
+#[derive(Clone, Copy, Debug)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    pub first: usize,
+    pub count: usize, // 0 for interior nodes
+}
+
+// Hierarchy over a mesh's triangles in object space. Holds triangle indices
+// only, so it stays valid while vertices move as long as refit() is called;
+// changing the indices needs a rebuild.
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub triangles: Vec<u32>,
+}
+
+fn triangle_bounds(mesh: &Mesh, i: usize) -> Aabb {
+    let (a, b, c) = mesh.triangle(i);
+    Aabb::from_points(&[a.pos.xyz(), b.pos.xyz(), c.pos.xyz()])
+}
+
+impl Bvh {
+    pub fn build(mesh: &Mesh, split: BvhSplit) -> Bvh {
+        let n = mesh.triangle_count();
+        let bounds: Vec<Aabb> = (0..n).map(|i| triangle_bounds(mesh, i)).collect();
+        let centroids: Vec<Vector3> = bounds.iter().map(|b| b.center()).collect();
+        let mut ret = Bvh { nodes: Vec::with_capacity(n * 2), triangles: (0..n as u32).collect() };
+        if n > 0 {
+            ret.build_node(&bounds, &centroids, 0, n, split);
+        }
+        ret
+    }
+
+    fn build_node(&mut self, bounds: &[Aabb], centroids: &[Vector3], start: usize, end: usize, split: BvhSplit) -> usize {
+        let index = self.nodes.len();
+        let mut node_bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &t in self.triangles[start..end].iter() {
+            node_bounds = node_bounds.merge(bounds[t as usize]);
+            centroid_bounds.expand(centroids[t as usize]);
+        }
+        self.nodes.push(BvhNode { bounds: node_bounds, first: start, count: end - start });
+        if end - start <= LEAF_SIZE {
+            return index;
+        }
+        let size = centroid_bounds.max - centroid_bounds.min;
+        let axis = match size.x >= size.y && size.x >= size.z {
+            true => { 0 }
+            false if size.y >= size.z => { 1 }
+            false => { 2 }
+        };
+        if size[axis] <= 0.0 {
+            // all centroids coincide, no split separates them
+            return index;
+        }
+        let mid = match split {
+            BvhSplit::Median => {
+                let mid = (start + end) / 2;
+                self.triangles[start..end].select_nth_unstable_by(mid - start, |a, b| {
+                    centroids[*a as usize][axis].total_cmp(&centroids[*b as usize][axis])
+                });
+                mid
+            }
+            BvhSplit::Sah => {
+                match self.sah_partition(bounds, centroids, index, axis, &centroid_bounds) {
+                    Some(mid) => { mid }
+                    None => { return index; }
+                }
+            }
+        };
+        self.build_node(bounds, centroids, start, mid, split);
+        let right = self.build_node(bounds, centroids, mid, end, split);
+        self.nodes[index].first = right;
+        self.nodes[index].count = 0;
+        index
+    }
+
+    // Bins centroids along axis, picks the cheapest bin boundary and
+    // partitions the node's range around it. None when keeping a leaf is cheaper.
+    fn sah_partition(&mut self, bounds: &[Aabb], centroids: &[Vector3], index: usize,
+                     axis: usize, centroid_bounds: &Aabb) -> Option<usize> {
+        let node = self.nodes[index];
+        let (start, end) = (node.first, node.first + node.count);
+        let lo = centroid_bounds.min[axis];
+        let scale = SAH_BINS as f32 / (centroid_bounds.max[axis] - lo);
+        let bin = |t: u32| ((centroids[t as usize][axis] - lo) * scale).min(SAH_BINS as f32 - 1.0) as usize;
+        let mut counts = [0usize; SAH_BINS];
+        let mut boxes = [Aabb::empty(); SAH_BINS];
+        for &t in self.triangles[start..end].iter() {
+            let b = bin(t);
+            counts[b] += 1;
+            boxes[b] = boxes[b].merge(bounds[t as usize]);
+        }
+        // cost of splitting after bin i, swept from both ends
+        let mut left_cost = [0.0f32; SAH_BINS - 1];
+        let (mut acc, mut n) = (Aabb::empty(), 0);
+        for i in 0..SAH_BINS - 1 {
+            acc = acc.merge(boxes[i]);
+            n += counts[i];
+            left_cost[i] = acc.surface_area() * n as f32;
+        }
+        let (mut acc, mut n) = (Aabb::empty(), 0);
+        let mut best = (f32::MAX, 0);
+        for i in (0..SAH_BINS - 1).rev() {
+            acc = acc.merge(boxes[i + 1]);
+            n += counts[i + 1];
+            let cost = left_cost[i] + acc.surface_area() * n as f32;
+            if n > 0 && n < end - start && cost < best.0 {
+                best = (cost, i);
+            }
+        }
+        if best.0 == f32::MAX {
+            return None;
+        }
+        // a traversal step costs about one triangle test; big leaves are
+        // split regardless
+        let split_cost = 1.0 + best.0 / node.bounds.surface_area();
+        if split_cost >= (end - start) as f32 && end - start <= LEAF_SIZE * 4 {
+            return None;
+        }
+        let mut mid = start;
+        for i in start..end {
+            if bin(self.triangles[i]) <= best.1 {
+                self.triangles.swap(i, mid);
+                mid += 1;
+            }
+        }
+        Some(mid)
+    }
+
+    // Recomputes every box from the mesh's current vertex positions. Cheaper
+    // than a rebuild but the tree gets looser the further vertices move.
+    pub fn refit(&mut self, mesh: &Mesh) {
+        // children always come after their parent
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            self.nodes[i].bounds = match node.count > 0 {
+                true => {
+                    let mut b = Aabb::empty();
+                    for &t in self.triangles[node.first..node.first + node.count].iter() {
+                        b = b.merge(triangle_bounds(mesh, t as usize));
+                    }
+                    b
+                }
+                false => { self.nodes[i + 1].bounds.merge(self.nodes[node.first].bounds) }
+            };
+        }
+    }
+
+    // Nearest hit, near child first, skipping boxes beyond the best hit so far.
+    pub fn raycast(&self, ray: &Ray, mesh: &Mesh) -> Option<RayHit> {
+        let mut best: Option<RayHit> = None;
+        if self.nodes.is_empty() {
+            return best;
+        }
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            let node = self.nodes[i];
+            match ray_aabb(ray, &node.bounds) {
+                Some((near, _)) if best.is_none_or(|h| near <= h.t) => {}
+                _ => { continue; }
+            }
+            if node.count > 0 {
+                for &t in self.triangles[node.first..node.first + node.count].iter() {
+                    let (a, b, c) = mesh.triangle(t as usize);
+                    if let Some((d, u, v)) = ray_triangle(ray, a.pos, b.pos, c.pos) {
+                        if best.is_none_or(|h| d < h.t) {
+                            best = Some(RayHit { triangle: t, t: d, barycentric: Vector3::new(1.0 - u - v, u, v) });
+                        }
+                    }
+                }
+                continue;
+            }
+            let (left, right) = (i + 1, node.first);
+            let dl = ray_aabb(ray, &self.nodes[left].bounds).map_or(f32::MAX, |h| h.0);
+            let dr = ray_aabb(ray, &self.nodes[right].bounds).map_or(f32::MAX, |h| h.0);
+            // pushed last is visited first
+            match dl <= dr {
+                true => { stack.push(right); stack.push(left); }
+                false => { stack.push(left); stack.push(right); }
+            }
+        }
+        best
+    }
+
+    // Fills out with the triangles whose leaf boxes overlap frustum, in
+    // ascending order so drawing them keeps the mesh's submesh order.
+    // Conservative like Frustum::intersects_aabb. out is cleared first; pass
+    // the same Vec every frame and this allocates nothing.
+    pub fn frustum_triangles(&self, frustum: &Frustum, out: &mut Vec<u32>) {
+        out.clear();
+        // nodes are depth first, so a subtree ends right after its last leaf
+        // and skipping it needs no stack
+        let mut i = 0;
+        while i < self.nodes.len() {
+            let node = self.nodes[i];
+            if !frustum.intersects_aabb(&node.bounds) {
+                i = self.last_leaf(i) + 1;
+                continue;
+            }
+            if node.count > 0 {
+                out.extend_from_slice(&self.triangles[node.first..node.first + node.count]);
+                i += 1;
+                continue;
+            }
+            if frustum.contains_aabb(&node.bounds) {
+                let last = self.last_leaf(i);
+                let (first, end) = (self.leftmost_triangle(i), self.nodes[last].first + self.nodes[last].count);
+                out.extend_from_slice(&self.triangles[first..end]);
+                i = last + 1;
+                continue;
+            }
+            i += 1;
+        }
+        out.sort_unstable();
+    }
+
+    // Leaves below a node own one contiguous run of triangles.
+    fn leftmost_triangle(&self, mut i: usize) -> usize {
+        while self.nodes[i].count == 0 {
+            i += 1;
+        }
+        self.nodes[i].first
+    }
+
+    fn last_leaf(&self, mut i: usize) -> usize {
+        while self.nodes[i].count == 0 {
+            i = self.nodes[i].first;
+        }
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bvh, BvhSplit};
+    use crate::bounds::{Aabb, Frustum};
+    use crate::matrix_calc::Matrix4f;
+    use crate::mesh::Mesh;
+    use crate::ray::{raycast_mesh, Ray, RayHit};
+    use crate::transform_calc::Transform;
+    use crate::vector_calc::Vector4f;
+
+    // Rays from a shell around the origin towards points near it, some
+    // missing the unit sphere. Deterministic so failures reproduce.
+    fn rays(count: usize) -> Vec<Ray> {
+        let mut seed: u32 = 12345;
+        let mut next = move || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0
+        };
+        (0..count).map(|_| {
+            let from = Vector4f::direction(next(), next(), next()).normalized() * 3.0;
+            let to = Vector4f::point(next() * 1.2, next() * 1.2, next() * 1.2);
+            Ray::new(Vector4f { w: 1.0, ..from }, to - Vector4f { w: 1.0, ..from })
+        }).collect()
+    }
+
+    // Nearest hit by testing every triangle.
+    fn brute_force(ray: &Ray, mesh: &Mesh) -> Option<RayHit> {
+        let mut plain = mesh.clone();
+        plain.bvh = None;
+        raycast_mesh(ray, &plain)
+    }
+
+    fn assert_same_hit(a: Option<RayHit>, b: Option<RayHit>) {
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                // rays through a shared edge may report either triangle
+                assert!((a.t - b.t).abs() < 1e-5, "{:?} vs {:?}", a, b);
+            }
+            (a, b) => { assert_eq!(a.is_some(), b.is_some(), "{:?} vs {:?}", a, b); }
+        }
+    }
+
+    #[test]
+    fn raycast_matches_brute_force() {
+        for &split in [BvhSplit::Median, BvhSplit::Sah].iter() {
+            let mesh = Mesh::uv_sphere(1.0, 16, 24);
+            let bvh = Bvh::build(&mesh, split);
+            assert!(bvh.nodes.len() > 1);
+            let mut hits = 0;
+            for ray in rays(500).iter() {
+                let expected = brute_force(ray, &mesh);
+                hits += expected.is_some() as usize;
+                assert_same_hit(bvh.raycast(ray, &mesh), expected);
+            }
+            // both outcomes are exercised
+            assert!(hits > 100 && hits < 500, "{} hits", hits);
+        }
+    }
+
+    #[test]
+    fn refit_follows_moved_vertices() {
+        for &split in [BvhSplit::Median, BvhSplit::Sah].iter() {
+            let mut mesh = Mesh::uv_sphere(1.0, 16, 24);
+            mesh.build_bvh(split);
+            // squash into an ellipsoid shifted along +X
+            for v in mesh.vertices.iter_mut() {
+                v.pos = Vector4f::point(v.pos.x * 2.0 + 3.0, v.pos.y, v.pos.z * 0.5);
+            }
+            // straight down at x = 4, outside every box before the refit
+            let ray = Ray::new(Vector4f::point(4.0, 0.1, 10.0), Vector4f::direction(0.0, 0.0, -1.0));
+            assert!(mesh.bvh.as_ref().unwrap().raycast(&ray, &mesh).is_none());
+            mesh.compute_bounds();
+            let bvh = mesh.bvh.as_ref().unwrap();
+            let hit = bvh.raycast(&ray, &mesh).unwrap();
+            let top = 0.5 * (1.0f32 - 0.25 - 0.01).sqrt();
+            assert!((hit.t - (10.0 - top)).abs() < 0.02, "{:?}", hit);
+            let root = bvh.nodes[0].bounds;
+            assert!((root.max.x - 5.0).abs() < 1e-5 && (root.min.x - 1.0).abs() < 1e-5, "{:?}", root);
+            let shifted: Vec<Ray> = rays(200).iter().map(|r| Ray::new(r.origin + Vector4f::direction(3.0, 0.0, 0.0), r.dir)).collect();
+            for ray in shifted.iter() {
+                assert_same_hit(bvh.raycast(ray, &mesh), brute_force(ray, &mesh));
+            }
+        }
+    }
+
+    #[test]
+    fn frustum_triangles_are_a_sorted_superset() {
+        let mesh = Mesh::uv_sphere(1.0, 16, 24);
+        let mut t = Transform::init(64, 48);
+        t.view.set_lookat(Vector4f::point(2.0, 0.5, 0.3), Vector4f::point(0.0, 1.5, 0.0), Vector4f::direction(0.0, 0.0, 1.0));
+        t.update();
+        let frustum = Frustum::from_matrix(t.transform);
+        let mut out = vec![7, 7, 7];
+        for &split in [BvhSplit::Median, BvhSplit::Sah].iter() {
+            Bvh::build(&mesh, split).frustum_triangles(&frustum, &mut out);
+            assert!(out.windows(2).all(|w| w[0] < w[1]));
+            assert!(!out.is_empty() && out.len() < mesh.triangle_count());
+            for i in 0..mesh.triangle_count() {
+                let (a, b, c) = mesh.triangle(i);
+                let bounds = Aabb::from_points(&[a.pos.xyz(), b.pos.xyz(), c.pos.xyz()]);
+                if frustum.intersects_aabb(&bounds) {
+                    assert!(out.binary_search(&(i as u32)).is_ok(), "triangle {} missing", i);
+                }
+            }
+        }
+        // everything inside gives every triangle, an empty BVH none
+        let all = Frustum::from_matrix(Matrix4f::scaling(0.01, 0.01, 0.01) * Matrix4f::translation(0.0, 0.0, 0.5));
+        Bvh::build(&mesh, BvhSplit::Sah).frustum_triangles(&all, &mut out);
+        assert_eq!(out, (0..mesh.triangle_count() as u32).collect::<Vec<u32>>());
+        Bvh::default().frustum_triangles(&all, &mut out);
+        assert!(out.is_empty());
+    }
+}
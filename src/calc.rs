@@ -1,7 +1,7 @@
 use std::borrow::Borrow;
 use crate::matrix_calc::Matrix4f;
+use crate::pixel;
 use crate::vertex::{Edge, Vertex};
-use crate::vector_calc::Vector4f;
 
 #[derive(Clone, Copy)]
 pub struct Trapezoid {
@@ -20,19 +20,71 @@ pub struct Scanline {
     pub w: i32,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct Color {
     pub r: f32,
     pub g: f32,
     pub b: f32,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct Texcoord {
     pub u: f32,
     pub v: f32,
 }
 
+impl Color {
+    pub fn new(r: f32, g: f32, b: f32) -> Color {
+        Color { r, g, b }
+    }
+
+    // 0x00RRGGBB, the framebuffer layout.
+    pub fn from_u32(hex: u32) -> Color {
+        pixel::unpack_rgb(hex)
+    }
+
+    pub fn to_u32(self) -> u32 {
+        pixel::pack_rgb(self)
+    }
+
+    pub fn lerp(&self, c: Color, t: f32) -> Color {
+        Color {
+            r: interp(self.r, c.r, t),
+            g: interp(self.g, c.g, t),
+            b: interp(self.b, c.b, t),
+        }
+    }
+}
+
+impl std::ops::Add for Color {
+    type Output = Color;
+    fn add(self, c: Color) -> Color {
+        Color { r: self.r + c.r, g: self.g + c.g, b: self.b + c.b }
+    }
+}
+
+impl std::ops::Sub for Color {
+    type Output = Color;
+    fn sub(self, c: Color) -> Color {
+        Color { r: self.r - c.r, g: self.g - c.g, b: self.b - c.b }
+    }
+}
+
+// Component-wise, for tinting by light or texture color.
+impl std::ops::Mul for Color {
+    type Output = Color;
+    fn mul(self, c: Color) -> Color {
+        Color { r: self.r * c.r, g: self.g * c.g, b: self.b * c.b }
+    }
+}
+
+impl std::ops::Mul<f32> for Color {
+    type Output = Color;
+    fn mul(self, f: f32) -> Color {
+        Color { r: self.r * f, g: self.g * f, b: self.b * f }
+    }
+}
+
 pub fn CMID(x: i32, min: i32, max: i32) -> i32{
     if x < min {
         return min;
@@ -52,49 +104,16 @@ pub fn interp(x1: f32, x2: f32, t: f32) -> f32 {
 }
 
 pub fn trapezoid_init() -> Trapezoid {
+    let edge = Edge {
+        v: Vertex::new(),
+        v1: Vertex::new(),
+        v2: Vertex::new(),
+    };
     Trapezoid {
         top: 0.0,
         bottom: 0.0,
-        left: Edge {
-            v: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            },
-            v1: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            },
-            v2: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            },
-        },
-        right: Edge {
-            v: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            },
-            v1: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            },
-            v2: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            },
-        }
+        left: edge,
+        right: edge,
     }
 }
 
@@ -209,6 +228,23 @@ pub fn trapezoid_init_scan_line(trap: &mut Trapezoid, scanline: &mut Scanline, y
     scanline.step.division(trap.left.v, trap.right.v, width);
 }
 
+// Walks the rows of trap that fall inside 0..height, handing each one's
+// scanline to draw. Shared by the device and the shadow map rasterizers.
+pub fn trapezoid_scan(trap: &mut Trapezoid, height: i32, mut draw: impl FnMut(Scanline)) {
+    let mut scanline = Scanline {
+        v: Vertex::new(),
+        step: Vertex::new(),
+        x: 0, y: 0, w: 0
+    };
+    let top = ((trap.top + 0.5) as i32).max(0);
+    let bottom = ((trap.bottom + 0.5) as i32).min(height);
+    for j in top..bottom {
+        trapezoid_edge_interp(trap, j as f32 + 0.5);
+        trapezoid_init_scan_line(trap, &mut scanline, j);
+        draw(scanline);
+    }
+}
+
 pub fn map(val: f64, start1: f64, end1: f64, start2: f64, end2: f64) -> f64 {
     start2 + (end2 - start2) * ((val - start1) / (end1 - start1))
 }
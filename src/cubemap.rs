@@ -0,0 +1,179 @@
+use std::f32::consts::PI;
+use std::io;
+use crate::calc::Color;
+use crate::texture::{Texture, TextureFormat};
+use crate::vector_calc::Vector4f;
+
+// Faces are named after the world axis they look along. Within a face u and
+// v follow the usual cubemap layout (OpenGL's table), so face images made
+// for a Y-up world show up rotated in this Z-up one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CubeFace {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+pub const CUBE_FACES: [CubeFace; 6] = [CubeFace::PosX, CubeFace::NegX, CubeFace::PosY, CubeFace::NegY, CubeFace::PosZ, CubeFace::NegZ];
+
+// Face and 0..1 texture coordinates hit by direction d (need not be unit).
+pub fn direction_to_face(d: Vector4f) -> (CubeFace, f32, f32) {
+    let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+    let (face, sc, tc, ma) = match ax >= ay && ax >= az {
+        true if d.x >= 0.0 => { (CubeFace::PosX, -d.z, -d.y, ax) }
+        true => { (CubeFace::NegX, d.z, -d.y, ax) }
+        false if ay >= az && d.y >= 0.0 => { (CubeFace::PosY, d.x, d.z, ay) }
+        false if ay >= az => { (CubeFace::NegY, d.x, -d.z, ay) }
+        false if d.z >= 0.0 => { (CubeFace::PosZ, d.x, -d.y, az) }
+        false => { (CubeFace::NegZ, -d.x, -d.y, az) }
+    };
+    if ma == 0.0 {
+        return (CubeFace::PosZ, 0.5, 0.5);
+    }
+    (face, (sc / ma + 1.0) * 0.5, (tc / ma + 1.0) * 0.5)
+}
+
+// Inverse of direction_to_face, not normalized.
+pub fn face_direction(face: CubeFace, u: f32, v: f32) -> Vector4f {
+    let (sc, tc) = (u * 2.0 - 1.0, v * 2.0 - 1.0);
+    match face {
+        CubeFace::PosX => { Vector4f::direction(1.0, -tc, -sc) }
+        CubeFace::NegX => { Vector4f::direction(-1.0, -tc, sc) }
+        CubeFace::PosY => { Vector4f::direction(sc, 1.0, tc) }
+        CubeFace::NegY => { Vector4f::direction(sc, -1.0, -tc) }
+        CubeFace::PosZ => { Vector4f::direction(sc, -tc, 1.0) }
+        CubeFace::NegZ => { Vector4f::direction(-sc, -tc, -1.0) }
+    }
+}
+
+// Equirectangular (u, v) for a world direction: longitude around +Z starting
+// at +X, v = 0 at the zenith.
+pub fn equirect_uv(d: Vector4f) -> (f32, f32) {
+    let d = d.normalized();
+    let u = 0.5 + d.y.atan2(d.x) / (2.0 * PI);
+    let v = 0.5 - d.z.clamp(-1.0, 1.0).asin() / PI;
+    (u, v)
+}
+
+// Six square faces in CUBE_FACES order.
+#[derive(Clone, Debug)]
+pub struct Cubemap {
+    pub faces: Vec<Texture>,
+}
+
+impl Cubemap {
+    pub fn from_faces(faces: [Texture; 6]) -> Cubemap {
+        Cubemap { faces: faces.to_vec() }
+    }
+
+    // Evaluates f at every texel centre's direction.
+    pub fn from_fn(size: usize, f: impl Fn(Vector4f) -> Color) -> Cubemap {
+        let size = size.max(1);
+        let mut faces = Vec::with_capacity(6);
+        for face in CUBE_FACES.iter() {
+            let mut tex = Texture::new(size, size, TextureFormat::Rgb8);
+            for y in 0..size {
+                for x in 0..size {
+                    let u = (x as f32 + 0.5) / size as f32;
+                    let v = (y as f32 + 0.5) / size as f32;
+                    tex.set(x, y, f(face_direction(*face, u, v).normalized()).to_u32());
+                }
+            }
+            faces.push(tex);
+        }
+        Cubemap { faces }
+    }
+
+    pub fn from_equirect(image: &Texture, size: usize) -> Cubemap {
+        let mut ret = Cubemap::from_fn(size, |d| {
+            let (u, v) = equirect_uv(d);
+            image.sample_color(u, v)
+        });
+        for face in ret.faces.iter_mut() {
+            face.srgb = image.srgb;
+        }
+        ret
+    }
+
+    // PPM files in CUBE_FACES order.
+    pub fn load(paths: [&str; 6]) -> io::Result<Cubemap> {
+        let mut faces = Vec::with_capacity(6);
+        for path in paths.iter() {
+            faces.push(Texture::load_ppm(path)?);
+        }
+        Ok(Cubemap { faces })
+    }
+
+    pub fn load_equirect(path: &str, size: usize) -> io::Result<Cubemap> {
+        Ok(Cubemap::from_equirect(&Texture::load_ppm(path)?, size))
+    }
+
+    pub fn face(&self, face: CubeFace) -> &Texture {
+        &self.faces[face as usize]
+    }
+
+    pub fn is_srgb(&self) -> bool {
+        self.faces[0].srgb
+    }
+
+    // Nearest texel along direction d, as stored (no sRGB decode).
+    pub fn sample(&self, d: Vector4f) -> Color {
+        let (face, u, v) = direction_to_face(d);
+        self.face(face).sample_color(u, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{direction_to_face, face_direction, CubeFace, Cubemap, CUBE_FACES};
+    use crate::texture::{Texture, TextureFormat};
+    use crate::vector_calc::Vector4f;
+
+    fn axis(face: CubeFace) -> Vector4f {
+        match face {
+            CubeFace::PosX => { Vector4f::direction(1.0, 0.0, 0.0) }
+            CubeFace::NegX => { Vector4f::direction(-1.0, 0.0, 0.0) }
+            CubeFace::PosY => { Vector4f::direction(0.0, 1.0, 0.0) }
+            CubeFace::NegY => { Vector4f::direction(0.0, -1.0, 0.0) }
+            CubeFace::PosZ => { Vector4f::direction(0.0, 0.0, 1.0) }
+            CubeFace::NegZ => { Vector4f::direction(0.0, 0.0, -1.0) }
+        }
+    }
+
+    #[test]
+    fn axes_hit_their_face_centers() {
+        // 5x5 faces, each a flat gray with its own marker in the center texel
+        let faces: Vec<Texture> = (0..6u32).map(|i| {
+            let mut tex = Texture::new(5, 5, TextureFormat::Rgb8);
+            for y in 0..5 {
+                for x in 0..5 {
+                    tex.set(x, y, 0x404040);
+                }
+            }
+            tex.set(2, 2, 0xff0000 | i);
+            tex
+        }).collect();
+        let sky = Cubemap { faces };
+        for (i, &face) in CUBE_FACES.iter().enumerate() {
+            let (hit, u, v) = direction_to_face(axis(face) * 3.0);
+            assert_eq!((hit, u, v), (face, 0.5, 0.5));
+            assert_eq!(sky.sample(axis(face)).to_u32(), 0xff0000 | i as u32, "{:?}", face);
+        }
+    }
+
+    #[test]
+    fn face_direction_inverts_direction_to_face() {
+        for &face in CUBE_FACES.iter() {
+            for &(u, v) in [(0.5, 0.5), (0.1, 0.2), (0.9, 0.3), (0.25, 0.95)].iter() {
+                let d = face_direction(face, u, v);
+                assert!(d.dot(axis(face)) > 0.0);
+                let (hit, bu, bv) = direction_to_face(d);
+                assert_eq!(hit, face);
+                assert!((bu - u).abs() < 1e-6 && (bv - v).abs() < 1e-6, "{:?} {} {} -> {} {}", face, u, v, bu, bv);
+            }
+        }
+    }
+}
@@ -1,72 +1,125 @@
-use std::borrow::BorrowMut;
+use std::collections::HashMap;
 use std::mem::swap;
-use minifb::*;
 use crate::transform_calc::Transform;
-use crate::{calc, HEIGHT, WIDTH};
-use crate::calc::{CMID, Color, Scanline, Texcoord, Trapezoid, trapezoid_edge_interp, trapezoid_init, trapezoid_init_scan_line, trapezoid_init_triangle};
+use crate::buffer::Buffer2D;
+use crate::bounds::{Aabb, Frustum};
+use crate::calc::{Color, Scanline, Texcoord, Trapezoid, trapezoid_init, trapezoid_init_triangle, trapezoid_scan};
+use crate::cubemap::Cubemap;
+use crate::fog::Fog;
+use crate::light::Light;
+use crate::material::{BlendMode, Material, ShadingModel};
 use crate::matrix_calc::Matrix4f;
-use crate::vector_calc::Vector4f;
-use crate::vertex::{Edge, Vertex};
+use crate::mesh::Mesh;
+use crate::pixel::{self, srgb_decode, srgb_encode, PixelFormat};
+use crate::scene::Scene;
+use crate::pick::{raycast_mesh, screen_barycentric, PickResult};
+use crate::postprocess::PostChain;
+use crate::shading::{decode_normal, light_incidence, normal_map_from_height, perturb_normal, cook_torrance, reflect, refract, sphere_map_uv};
+use crate::shadow::ShadowMap;
+use crate::tonemap::{tone_map, ToneMap};
+use crate::texture::{Texture, TextureAddress, TextureHandle, TextureRegistry};
+use crate::vector_calc::{Vector2, Vector3, Vector4f};
+use crate::vertex::Vertex;
 
 pub struct Device {
     pub transform: Transform,
-    pub window: minifb::Window, // get_size = (width, height)
-    pub framebuf: Vec<u32>,
-    pub texture: Vec<Vec<u32>>,
-    pub zbuffer: Vec<Vec<f32>>,
-    pub tex_width: i32,
-    pub tex_height: i32,
-    pub max_u: f32,
-    pub max_v: f32,
+    pub framebuf: Buffer2D<u32>,
+    pub textures: TextureRegistry,
+    pub texture: Option<TextureHandle>, // bound for RENDER_STATE_TEXTURE
+    pub zbuffer: Buffer2D<f32>, // 1/w, 0 where nothing was drawn
+    pub hdr: Option<Buffer2D<Color>>, // linear color target, see resolve()
+    pub exposure: f32,
+    pub tone_map: ToneMap,
+    pub fog: Option<Fog>,
+    pub skybox: Option<Cubemap>, // also the environment for reflections
+    pub post: PostChain, // run by post_process()
+    pub srgb: bool, // linear shading and blending, sRGB in textures and output
     pub render_state: i32,
     pub background: u32,
     pub foreground: u32,
-    pub mesh: Vec<Vertex>
+    pub mesh: Vec<Vertex>,
+    pub lights: Vec<Light>,
+    pub culling: bool,
+    pub stats: RenderStats,
+    pub ambient: Color,
+    pub shadow_maps: Vec<Option<ShadowMap>>, // one slot per entry in lights
+    pub shadow_map_size: usize,
+    pub normal_map: Option<TextureHandle>, // used when the material has none
+    pub material: Option<Material>,   // bound for the current draw
+    pub toon_bands: usize,
+    pub outline_color: Color,
+    pub outline_width: f32,    // hull offset along normals, object units
+    pub outline_depth: f32,    // relative view-depth jump that counts as an edge
+    pub outline_normal: f32,   // normals closer than this cosine are no edge
+    pub normal_buffer: Option<Buffer2D<Vector3>>, // world normals, for edge outlines
+    pub overdraw: Option<Buffer2D<u32>>, // fragments written per pixel this frame
+    pub object_id: u32, // tagged onto id_buffer writes; draw_scene sets the node index
+    pub id_buffer: Option<Buffer2D<Option<PickResult>>>, // see set_id_buffer()
+    triangle_id: u32,
+    pick_tri: [Vector4f; 3], // screen x, y and clip w of the current triangle
+    uv_grad: [Vector3; 2], // d(u/w, v/w, 1/w) per pixel along x and y, current triangle
+    visible_triangles: Vec<u32>, // BVH query scratch, reused across draws
+    primitive_id: u32, // counts draw_primitive calls since clear()
+    back_facing: bool,
+    cull_front: bool,
+    unlit: bool,
+}
+
+// Per-frame counters, reset by clear().
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RenderStats {
+    pub objects_drawn: usize,
+    pub objects_culled: usize,
+    pub triangles_drawn: usize,
+    pub triangles_culled: usize,
 }
 
 const RENDER_STATE_WIREFRAME: i32 = 1;
 const RENDER_STATE_TEXTURE: i32 = 2;
 const RENDER_STATE_COLOR: i32 = 4;
+const RENDER_STATE_TOON: i32 = 8;
+const RENDER_STATE_OUTLINE_HULL: i32 = 16;
+const RENDER_STATE_OUTLINE_EDGE: i32 = 32;
+const RENDER_STATE_DEBUG_DEPTH: i32 = 64;
+const RENDER_STATE_DEBUG_NORMALS: i32 = 128;      // world space
+const RENDER_STATE_DEBUG_VIEW_NORMALS: i32 = 256;
+const RENDER_STATE_DEBUG_UV: i32 = 512;
+const RENDER_STATE_DEBUG_OVERDRAW: i32 = 1024;
+const RENDER_STATE_DEBUG_TRIANGLES: i32 = 2048;
+const RENDER_STATE_DEBUG: i32 = RENDER_STATE_DEBUG_DEPTH | RENDER_STATE_DEBUG_NORMALS | RENDER_STATE_DEBUG_VIEW_NORMALS
+    | RENDER_STATE_DEBUG_UV | RENDER_STATE_DEBUG_OVERDRAW | RENDER_STATE_DEBUG_TRIANGLES;
 
-pub struct RGB {
-    pub(crate) R: u32,
-    pub(crate) G: u32,
-    pub(crate) B: u32,
-}
-
-pub fn hex_to_rgb(hex: u32) -> RGB {
-    let ret = RGB {
-        R: ((hex >> 16) & 0xFF)  / 255. as u32,
-        G: ((hex >> 8) & 0xFF) / 255. as u32,
-        B: ((hex) & 0xFF) / 255. as u32,
-    };
-    return ret;
-}
-
-pub fn rgb_to_hex(rgb: RGB) -> u32 {
-    return (((rgb.R & 0xFF) << 16) + ((rgb.G & 0xFF) << 8) + (rgb.B & 0xFF)) as u32;
+// Screen-space x and y gradients of (u/w, v/w, 1/w) over a triangle whose
+// vertices went through rhw_init. Zero for degenerate triangles.
+fn screen_gradients(a: &Vertex, b: &Vertex, c: &Vertex) -> [Vector3; 2] {
+    let (e1, e2) = (b.pos.xy() - a.pos.xy(), c.pos.xy() - a.pos.xy());
+    let det = e1.cross(e2);
+    if det == 0.0 {
+        return [Vector3::default(); 2];
+    }
+    let attr = |v: &Vertex| Vector3::new(v.tc.u, v.tc.v, v.rhw);
+    let (d1, d2) = (attr(b) - attr(a), attr(c) - attr(a));
+    let inv = 1.0 / det;
+    [(d1 * e2.y - d2 * e1.y) * inv, (d2 * e1.x - d1 * e2.x) * inv]
 }
 
-
 impl Device {
-    pub fn init(name: &str, width: usize, height: usize) -> Device {
-        let mut device : Device = Device {
-            transform: Transform::init(),
-            window: minifb::Window::new(
-                name,
-                width,
-                height,
-                WindowOptions {
-                    scale: minifb::Scale::X1,
-                    ..WindowOptions::default()
-                },).unwrap(),
-            framebuf: vec![0b00000000_00000000_00000000_00000000; width * height],
-            texture: vec![vec![0; 256]; 256],
-            zbuffer: vec![vec![0.; height]; width],
-            tex_width: 2,
-            tex_height: 2,
-            max_u: 1.0,
-            max_v: 1.0,
+    // Renders into framebuf only; showing it is up to the caller, so a
+    // Device works without a window or display.
+    pub fn init(width: usize, height: usize) -> Device {
+        let device : Device = Device {
+            transform: Transform::init(width, height),
+            framebuf: Buffer2D::new(width, height, 0b00000000_00000000_00000000_00000000),
+            textures: TextureRegistry::new(),
+            texture: None,
+            zbuffer: Buffer2D::new(width, height, 0.),
+            hdr: None,
+            exposure: 1.0,
+            tone_map: ToneMap::Aces,
+            fog: None,
+            skybox: None,
+            post: PostChain::new(),
+            srgb: false,
             render_state: 0,
             background: 0b00000000_00000000_00000000_00000000,
             foreground: 0,
@@ -74,78 +127,263 @@ impl Device {
                 Vertex {
                     pos: Vector4f { x: -1.0, y: -1.0, z: 1.0, w: 1.0 },
                     tc: Texcoord { u: 0.0, v: 0.0 },
-                    color: Color { r: 1.0, g: 0.2, b: 0.2 }, rhw: 1.0
+                    color: Color { r: 1.0, g: 0.2, b: 0.2 }, rhw: 1.0, ..Vertex::new()
                 },
                 Vertex {
                     pos: Vector4f { x: 1.0, y: -1.0, z: 1.0, w: 1.0 },
                     tc: Texcoord { u: 0.0, v: 1.0 },
-                    color: Color { r: 0.2, g: 1.0, b: 0.2 }, rhw: 1.0
+                    color: Color { r: 0.2, g: 1.0, b: 0.2 }, rhw: 1.0, ..Vertex::new()
                 },
                 Vertex {
                     pos: Vector4f { x: 1.0, y: 1.0, z: 1.0, w: 1.0 },
                     tc: Texcoord { u: 1.0, v: 1.0 },
-                    color: Color { r: 0.2, g: 0.2, b: 1.0 }, rhw: 1.0
+                    color: Color { r: 0.2, g: 0.2, b: 1.0 }, rhw: 1.0, ..Vertex::new()
                 },
                 Vertex {
                     pos: Vector4f { x: -1.0, y: 1.0, z: 1.0, w: 1.0 },
                     tc: Texcoord { u: 1.0, v: 0.0 },
-                    color: Color { r: 1.0, g: 0.2, b: 1.0 }, rhw: 1.0
+                    color: Color { r: 1.0, g: 0.2, b: 1.0 }, rhw: 1.0, ..Vertex::new()
                 },
                 Vertex {
                     pos: Vector4f { x: -1.0, y: -1.0, z: -1.0, w: 1.0 },
                     tc: Texcoord { u: 0.0, v: 0.0 },
-                    color: Color { r: 1.0, g: 1.0, b: 0.2 }, rhw: 1.0
+                    color: Color { r: 1.0, g: 1.0, b: 0.2 }, rhw: 1.0, ..Vertex::new()
                 },
                 Vertex {
                     pos: Vector4f { x: 1.0, y: -1.0, z: -1.0, w: 1.0 },
                     tc: Texcoord { u: 0.0, v: 1.0 },
-                    color: Color { r: 0.2, g: 1.0, b: 1.0 }, rhw: 1.0
+                    color: Color { r: 0.2, g: 1.0, b: 1.0 }, rhw: 1.0, ..Vertex::new()
                 },
                 Vertex {
                     pos: Vector4f { x: 1.0, y: 1.0, z: -1.0, w: 1.0 },
                     tc: Texcoord { u: 1.0, v: 1.0 },
-                    color: Color { r: 1.0, g: 0.3, b: 0.3 }, rhw: 1.0
+                    color: Color { r: 1.0, g: 0.3, b: 0.3 }, rhw: 1.0, ..Vertex::new()
                 },
                 Vertex {
                     pos: Vector4f { x: -1.0, y: 1.0, z: -1.0, w: 1.0 },
                     tc: Texcoord { u: 1.0, v: 0.0 },
-                    color: Color { r: 0.2, g: 1.0, b: 0.3 }, rhw: 1.0
+                    color: Color { r: 0.2, g: 1.0, b: 0.3 }, rhw: 1.0, ..Vertex::new()
                 },
-            ]
+            ],
+            lights: Vec::new(),
+            culling: true,
+            stats: RenderStats::default(),
+            ambient: Color { r: 0.2, g: 0.2, b: 0.2 },
+            shadow_maps: Vec::new(),
+            shadow_map_size: 512,
+            normal_map: None,
+            material: None,
+            toon_bands: 3,
+            outline_color: Color { r: 0.0, g: 0.0, b: 0.0 },
+            outline_width: 0.03,
+            outline_depth: 0.1,
+            outline_normal: 0.7,
+            normal_buffer: None,
+            overdraw: None,
+            object_id: 0,
+            id_buffer: None,
+            triangle_id: 0,
+            pick_tri: [Vector4f::default(); 3],
+            uv_grad: [Vector3::default(); 2],
+            visible_triangles: Vec::new(),
+            primitive_id: 0,
+            back_facing: false,
+            cull_front: false,
+            unlit: false,
         };
         return device;
     }
 
-    pub fn set_texture(&mut self, w: i32, h: i32) {
-        self.tex_width = w;
-        self.tex_height = h;
-        self.max_u = (w - 1) as f32;
-        self.max_v = (h - 1) as f32;
+    pub fn add_texture(&mut self, texture: Texture) -> TextureHandle {
+        self.textures.insert(texture)
+    }
+
+    pub fn set_texture(&mut self, texture: TextureHandle) {
+        self.texture = Some(texture);
     }
 
     pub fn clear(&mut self, mode: i32) {
-        let mut buf: Vec<u32>= vec![0; (WIDTH * HEIGHT)];
-        for y in 0..HEIGHT {
-            let mut cc = ((HEIGHT - 1 - y) * 230 / (HEIGHT - 1)) as u32;
+        let height = self.framebuf.height;
+        for y in 0..height {
+            let mut cc = ((height - 1 - y) * 230 / (height - 1).max(1)) as u32;
             cc = (cc << 16) | (cc << 8) | cc;
             if mode == 0 {
                 cc = self.background;
             }
-            for x in 0..WIDTH {
-                buf[y * WIDTH + x] = cc;
+            for c in self.framebuf.row_mut(y).iter_mut() {
+                *c = cc;
+            }
+            if let Some(hdr) = self.hdr.as_mut() {
+                let linear = match self.srgb {
+                    true => { srgb_decode(Color::from_u32(cc)) }
+                    false => { Color::from_u32(cc) }
+                };
+                for c in hdr.row_mut(y).iter_mut() {
+                    *c = linear;
+                }
+            }
+        }
+        self.zbuffer.fill(0.);
+        match self.render_state & RENDER_STATE_OUTLINE_EDGE > 0 {
+            true => {
+                let (w, h) = (self.zbuffer.width, self.zbuffer.height);
+                self.normal_buffer.get_or_insert_with(|| Buffer2D::new(w, h, Vector3::default())).fill(Vector3::default());
             }
+            false => { self.normal_buffer = None; }
         }
-        for x in 0..WIDTH {
-            for y in 0..HEIGHT {
-                self.zbuffer[x][y] = 0.;
+        match self.render_state & RENDER_STATE_DEBUG_OVERDRAW > 0 {
+            true => {
+                let (w, h) = (self.zbuffer.width, self.zbuffer.height);
+                self.overdraw.get_or_insert_with(|| Buffer2D::new(w, h, 0)).fill(0);
             }
+            false => { self.overdraw = None; }
         }
-        self.framebuf = buf;
+        self.primitive_id = 0;
+        if let Some(ids) = self.id_buffer.as_mut() {
+            ids.fill(None);
+        }
+        self.stats = RenderStats::default();
+    }
+
+    // The framebuffer re-encoded for displays or files other than the window.
+    pub fn read_pixels(&self, format: PixelFormat) -> Vec<u8> {
+        pixel::encode(&self.framebuf, format)
     }
 
     pub fn pixel(&mut self, x: usize, y: usize, color: u32) {
-        if y < WIDTH && x < HEIGHT {
-            self.framebuf[y * WIDTH + x] = color;
+        match self.hdr.as_mut() {
+            Some(hdr) => {
+                let c = match self.srgb {
+                    true => { srgb_decode(Color::from_u32(color)) }
+                    false => { Color::from_u32(color) }
+                };
+                hdr.set(x, y, c);
+            }
+            None => { self.framebuf.set(x, y, color); }
+        }
+    }
+
+    // Switches drawing between the u32 framebuffer and a float target that
+    // resolve() tone maps into it.
+    pub fn set_hdr(&mut self, on: bool) {
+        self.hdr = match on {
+            true => { Some(Buffer2D::new(self.framebuf.width, self.framebuf.height, Color::default())) }
+            false => { None }
+        };
+    }
+
+    // Exposure and tone mapping from the HDR target into framebuf; does
+    // nothing when HDR is off or a debug view wrote framebuf directly. Call
+    // after drawing, before overlays.
+    pub fn resolve(&mut self) {
+        let hdr = match self.hdr.as_ref() {
+            Some(hdr) if self.render_state & RENDER_STATE_DEBUG == 0 => { hdr }
+            _ => { return; }
+        };
+        for (dst, c) in self.framebuf.as_mut_slice().iter_mut().zip(hdr.as_slice().iter()) {
+            let c = tone_map(self.tone_map, *c, self.exposure);
+            *dst = match self.srgb {
+                true => { srgb_encode(c).to_u32() }
+                false => { c.to_u32() }
+            };
+        }
+    }
+
+    // Writes a linear image, such as PathTracer::image(), into the active
+    // target the way drawing would. Sizes must match framebuf.
+    pub fn present(&mut self, image: &Buffer2D<Color>) {
+        for y in 0..image.height.min(self.framebuf.height) {
+            for x in 0..image.width.min(self.framebuf.width) {
+                self.write_color(x, y, image[(x, y)]);
+            }
+        }
+    }
+
+    // Whether surfaces take their base color from textures rather than
+    // vertex colors in the current render state.
+    pub fn textured(&self) -> bool {
+        self.render_state & RENDER_STATE_TEXTURE > 0
+    }
+
+    // Environment color along a world direction, decoded to linear like
+    // sample_color.
+    pub fn sample_environment(&self, d: Vector4f) -> Option<Color> {
+        let sky = self.skybox.as_ref()?;
+        let c = sky.sample(d);
+        match self.srgb && sky.is_srgb() {
+            true => { Some(srgb_decode(c)) }
+            false => { Some(c) }
+        }
+    }
+
+    // Paints the skybox over the whole target using only the camera
+    // rotation. Call after clear() and before drawing geometry; depth is left
+    // at 0 so everything lands in front of it.
+    pub fn draw_skybox(&mut self) {
+        if self.skybox.is_none() {
+            return;
+        }
+        let mut view = self.transform.view;
+        view.m[3] = [0.0, 0.0, 0.0, 1.0];
+        let inv = match (view * self.transform.projection).inverted() {
+            Some(inv) => { inv }
+            None => { return; }
+        };
+        let (w, h) = (self.framebuf.width, self.framebuf.height);
+        for y in 0..h {
+            for x in 0..w {
+                let nx = (x as f32 + 0.5) / w as f32 * 2.0 - 1.0;
+                let ny = 1.0 - (y as f32 + 0.5) / h as f32 * 2.0;
+                let far = Vector4f::point(nx, ny, 1.0) * inv;
+                let near = Vector4f::point(nx, ny, 0.0) * inv;
+                let d = far.perspective_divide() - near.perspective_divide();
+                let c = self.sample_environment(d).unwrap_or_default();
+                self.write_color(x, y, c);
+            }
+        }
+    }
+
+    // Runs the enabled post passes over linear color: the HDR target before
+    // tone mapping, or framebuf decoded and re-encoded. Call before resolve().
+    pub fn post_process(&mut self) {
+        if !self.post.is_active() {
+            return;
+        }
+        let (w, h) = (self.framebuf.width, self.framebuf.height);
+        let mut color = Buffer2D::new(w, h, Color::default());
+        for y in 0..h {
+            for x in 0..w {
+                color[(x, y)] = self.read_color(x, y);
+            }
+        }
+        self.post.apply(&mut color, &self.zbuffer);
+        for y in 0..h {
+            for x in 0..w {
+                self.write_color(x, y, color[(x, y)]);
+            }
+        }
+    }
+
+    // Linear color at (x, y) in whichever target is active.
+    fn read_color(&self, x: usize, y: usize) -> Color {
+        match (self.hdr.as_ref(), self.srgb) {
+            (Some(hdr), _) => { hdr[(x, y)] }
+            (None, true) => { srgb_decode(Color::from_u32(self.framebuf[(x, y)])) }
+            (None, false) => { Color::from_u32(self.framebuf[(x, y)]) }
+        }
+    }
+
+    // Debug views show their values as is: no sRGB encoding, exposure or
+    // tone mapping.
+    fn write_debug(&mut self, x: usize, y: usize, c: Color) {
+        self.framebuf[(x, y)] = c.to_u32();
+    }
+
+    fn write_color(&mut self, x: usize, y: usize, c: Color) {
+        match (self.hdr.as_mut(), self.srgb) {
+            (Some(hdr), _) => { hdr[(x, y)] = c; }
+            (None, true) => { self.framebuf[(x, y)] = srgb_encode(c).to_u32(); }
+            (None, false) => { self.framebuf[(x, y)] = c.to_u32(); }
         }
     }
 
@@ -232,52 +470,353 @@ impl Device {
         }
     }
 
-    pub fn texture_read(&self, mut u: f32, mut v: f32) -> u32 {
-        let mut x;
-        let mut y;
-        u *= self.max_u;
-        v *= self.max_v;
-        x = (u + 0.5) as i32;
-        y = (v + 0.5) as i32;
-        x = CMID(x, 0, self.tex_width - 1);
-        y = CMID(y, 0, self.tex_height - 1);
-        return self.texture[y as usize][x as usize];
+    pub fn texture_read(&self, u: f32, v: f32) -> u32 {
+        self.sample_slot(self.texture, u, v, 0.0).unwrap_or(0)
+    }
+
+    // Texel from a registry texture, None when the slot is empty or the
+    // texture has been freed. footprint is the uv distance one pixel spans
+    // (see uv_footprint) and picks the mip level; 0 samples level 0.
+    pub fn sample_slot(&self, slot: Option<TextureHandle>, u: f32, v: f32, footprint: f32) -> Option<u32> {
+        slot.and_then(|h| self.textures.get(h)).map(|tex| tex.sample_level(u, v, tex.lod(footprint)))
+    }
+
+    // Like sample_slot but as a color, decoded to linear for sRGB textures
+    // when the sRGB pipeline is on.
+    pub fn sample_color(&self, slot: Option<TextureHandle>, u: f32, v: f32, footprint: f32) -> Option<Color> {
+        let tex = slot.and_then(|h| self.textures.get(h))?;
+        let c = Color::from_u32(tex.sample_level(u, v, tex.lod(footprint)));
+        match self.srgb && tex.srgb {
+            true => { Some(srgb_decode(c)) }
+            false => { Some(c) }
+        }
+    }
+
+    // How far uv moves over one pixel at scanline vertex v, the larger of
+    // the x and y steps. Undoes the perspective divide on the per-triangle
+    // gradients of u/w, v/w and 1/w.
+    fn uv_footprint(&self, v: &Vertex, w1: f32) -> f32 {
+        let (u, tv) = (v.tc.u * w1, v.tc.v * w1);
+        let mut ret: f32 = 0.0;
+        for g in self.uv_grad.iter() {
+            let step = Vector2::new(g.x - u * g.z, g.y - tv * g.z) * w1;
+            ret = ret.max(step.length());
+        }
+        ret
+    }
+
+    // Material factors, emissive and, when lights exist, ambient plus
+    // Lambert diffuse and Blinn-Phong specular from every light, attenuated by
+    // its shadow map. v is a scanline vertex still divided by w, w1 undoes that.
+    pub fn shade(&self, base: Color, v: &Vertex, w1: f32) -> Color {
+        let mat = self.material.unwrap_or_default();
+        let (u, tv) = (v.tc.u * w1, v.tc.v * w1);
+        let footprint = self.uv_footprint(v, w1);
+        let albedo = base * mat.base_color;
+        let emissive = match self.sample_color(mat.emissive_map, u, tv, footprint) {
+            Some(texel) => { mat.emissive * texel }
+            None => { mat.emissive }
+        };
+        let mut n = (v.normal * w1).normalized();
+        if n.length() == 0.0 {
+            return albedo + emissive;
+        }
+        if self.back_facing {
+            n = -n;
+        }
+        let normal_texel = match self.sample_slot(mat.normal_map, u, tv, footprint) {
+            Some(texel) => { Some(texel) }
+            None => { self.sample_slot(self.normal_map, u, tv, footprint) }
+        };
+        if let Some(texel) = normal_texel {
+            n = perturb_normal(n, v.tangent * w1, decode_normal(texel));
+        }
+        let mut p = v.world * w1;
+        p.w = 1.0;
+        if self.lights.is_empty() {
+            return self.mix_environment(&mat, albedo + emissive, n, p);
+        }
+        if self.render_state & RENDER_STATE_TOON > 0 {
+            let c = self.shade_toon(&mat, albedo, n, p);
+            return self.mix_environment(&mat, c + emissive, n, p);
+        }
+        if mat.shading == ShadingModel::Pbr {
+            let c = self.shade_pbr(&mat, albedo, n, p, Texcoord { u, v: tv }, footprint);
+            return self.mix_environment(&mat, c + emissive, n, p);
+        }
+        let specular = match self.sample_slot(mat.specular_map, u, tv, footprint) {
+            Some(texel) => { mat.specular * Color::from_u32(texel) }
+            None => { mat.specular }
+        };
+        let has_specular = specular.r + specular.g + specular.b > 0.0;
+        let view = (self.transform.eye - p).normalized();
+        let mut diffuse = self.ambient;
+        let mut spec = Color { r: 0.0, g: 0.0, b: 0.0 };
+        for (i, light) in self.lights.iter().enumerate() {
+            let (l, intensity) = light_incidence(light, p);
+            let n_dot_l = n.dot(l);
+            if n_dot_l <= 0.0 || intensity <= 0.0 {
+                continue;
+            }
+            let visibility = match self.shadow_maps.get(i) {
+                Some(Some(map)) => { map.visibility(p, n_dot_l) }
+                _ => { 1.0 }
+            };
+            let radiance = light.color * (intensity * visibility);
+            diffuse = diffuse + radiance * n_dot_l;
+            if has_specular {
+                let h = (l + view).normalized();
+                spec = spec + radiance * n.dot(h).max(0.0).powf(mat.shininess);
+            }
+        }
+        self.mix_environment(&mat, albedo * diffuse + specular * spec + emissive, n, p)
+    }
+
+    // Cel shading: each light's diffuse term snapped to toon_bands levels and
+    // a hard-edged highlight.
+    fn shade_toon(&self, mat: &Material, albedo: Color, n: Vector4f, p: Vector4f) -> Color {
+        let bands = self.toon_bands.max(1) as f32;
+        let view = (self.transform.eye - p).normalized();
+        let has_specular = mat.specular.r + mat.specular.g + mat.specular.b > 0.0;
+        let mut diffuse = self.ambient;
+        let mut spec = Color::default();
+        for (i, light) in self.lights.iter().enumerate() {
+            let (l, intensity) = light_incidence(light, p);
+            let n_dot_l = n.dot(l);
+            if n_dot_l <= 0.0 || intensity <= 0.0 {
+                continue;
+            }
+            let visibility = match self.shadow_maps.get(i) {
+                Some(Some(map)) => { map.visibility(p, n_dot_l) }
+                _ => { 1.0 }
+            };
+            let level = ((n_dot_l * visibility).min(1.0) * bands).ceil() / bands;
+            diffuse = diffuse + light.color * (intensity * level);
+            if has_specular && visibility > 0.5 {
+                let h = (l + view).normalized();
+                if n.dot(h).max(0.0).powf(mat.shininess) > 0.5 {
+                    spec = spec + light.color * intensity;
+                }
+            }
+        }
+        albedo * diffuse + mat.specular * spec
+    }
+
+    // Metallic-roughness lighting from every light, with the constant
+    // ambient scaled by ambient occlusion. tc is the perspective-correct uv.
+    fn shade_pbr(&self, mat: &Material, albedo: Color, n: Vector4f, p: Vector4f, tc: Texcoord, footprint: f32) -> Color {
+        let (mut metallic, mut roughness) = (mat.metallic, mat.roughness);
+        if let Some(texel) = self.sample_slot(mat.metallic_roughness_map, tc.u, tc.v, footprint) {
+            roughness *= ((texel >> 8) & 0xFF) as f32 / 255.0;
+            metallic *= (texel & 0xFF) as f32 / 255.0;
+        }
+        let ao = match self.sample_slot(mat.ao_map, tc.u, tc.v, footprint) {
+            Some(texel) => { mat.ao * ((texel >> 16) & 0xFF) as f32 / 255.0 }
+            None => { mat.ao }
+        };
+        let view = (self.transform.eye - p).normalized();
+        let mut ret = albedo * self.ambient * ao;
+        for (i, light) in self.lights.iter().enumerate() {
+            let (l, intensity) = light_incidence(light, p);
+            let n_dot_l = n.dot(l);
+            if n_dot_l <= 0.0 || intensity <= 0.0 {
+                continue;
+            }
+            let visibility = match self.shadow_maps.get(i) {
+                Some(Some(map)) => { map.visibility(p, n_dot_l) }
+                _ => { 1.0 }
+            };
+            let radiance = light.color * (intensity * visibility);
+            ret = ret + radiance * cook_torrance(n, view, l, albedo, metallic, roughness.clamp(0.0, 1.0));
+        }
+        ret
+    }
+
+    fn environment(&self, mat: &Material, d: Vector4f) -> Option<Color> {
+        if mat.env_map.is_none() {
+            return self.sample_environment(d);
+        }
+        let (u, v) = sphere_map_uv((d * self.transform.view).normalized());
+        self.sample_color(mat.env_map, u, v, 0.0)
+    }
+
+    // Blends reflected and refracted environment into c by the material's
+    // factors; n is the shading normal at world position p.
+    fn mix_environment(&self, mat: &Material, c: Color, n: Vector4f, p: Vector4f) -> Color {
+        if mat.reflectivity <= 0.0 && mat.refractivity <= 0.0 {
+            return c;
+        }
+        let i = (p - self.transform.eye).normalized();
+        let r = reflect(i, n);
+        let mut ret = c;
+        if mat.refractivity > 0.0 {
+            let eta = match self.back_facing {
+                true => { mat.ior }
+                false => { 1.0 / mat.ior }
+            };
+            let t = refract(i, n, eta).unwrap_or(r);
+            if let Some(env) = self.environment(mat, t) {
+                ret = ret.lerp(env, mat.refractivity);
+            }
+        }
+        if mat.reflectivity > 0.0 {
+            if let Some(env) = self.environment(mat, r) {
+                ret = ret.lerp(env, mat.reflectivity);
+            }
+        }
+        ret
+    }
+
+    // Per-fragment color for the debug render states; None leaves the pixel to
+    // the full-screen pass in draw_debug_buffers.
+    fn debug_color(&self, v: &Vertex, w1: f32) -> Option<Color> {
+        let state = self.render_state;
+        let as_color = |n: Vector4f| Color::new(n.x * 0.5 + 0.5, n.y * 0.5 + 0.5, n.z * 0.5 + 0.5);
+        if state & RENDER_STATE_DEBUG_NORMALS > 0 {
+            return Some(as_color((v.normal * w1).normalized()));
+        }
+        if state & RENDER_STATE_DEBUG_VIEW_NORMALS > 0 {
+            let n = Vector4f { w: 0.0, ..v.normal * w1 } * self.transform.view;
+            return Some(as_color(n.normalized()));
+        }
+        if state & RENDER_STATE_DEBUG_UV > 0 {
+            let (u, tv) = (v.tc.u * w1, v.tc.v * w1);
+            return Some(Color::new(u - u.floor(), tv - tv.floor(), 0.0));
+        }
+        if state & RENDER_STATE_DEBUG_TRIANGLES > 0 {
+            // integer hash so neighbouring ids get unrelated colors
+            let mut h = self.primitive_id.wrapping_mul(0x9E3779B1);
+            h ^= h >> 15;
+            h = h.wrapping_mul(0x85EBCA77);
+            h ^= h >> 13;
+            return Some(Color::from_u32(h & 0xFFFFFF));
+        }
+        None
+    }
+
+    // Full-screen debug views built from whole buffers: depth linearized
+    // from zbuffer and stretched over the frame's depth range (near bright),
+    // and the overdraw count as a heatmap.
+    pub fn draw_debug_buffers(&mut self) {
+        let (w, h) = (self.zbuffer.width, self.zbuffer.height);
+        if self.render_state & RENDER_STATE_DEBUG_DEPTH > 0 {
+            let depths = self.zbuffer.as_slice().iter().filter(|rhw| **rhw > 0.0).map(|rhw| 1.0 / rhw);
+            let (near, far) = depths.fold((f32::MAX, 0.0f32), |(lo, hi), d| (lo.min(d), hi.max(d)));
+            for y in 0..h {
+                for x in 0..w {
+                    let rhw = self.zbuffer[(x, y)];
+                    let g = match rhw > 0.0 {
+                        true => { 1.0 - (1.0 / rhw - near) / (far - near).max(1e-6) }
+                        false => { 0.0 }
+                    };
+                    self.write_debug(x, y, Color::new(g, g, g));
+                }
+            }
+        }
+        if let Some(overdraw) = self.overdraw.take() {
+            // black, blue, green, yellow, red at 0, 1, 2, 3 and 4+ writes
+            let ramp = [Color::new(0.0, 0.0, 0.0), Color::new(0.0, 0.0, 1.0), Color::new(0.0, 1.0, 0.0),
+                        Color::new(1.0, 1.0, 0.0), Color::new(1.0, 0.0, 0.0)];
+            for y in 0..h {
+                for x in 0..w {
+                    let n = (overdraw[(x, y)] as usize).min(ramp.len() - 1);
+                    self.write_debug(x, y, ramp[n]);
+                }
+            }
+            self.overdraw = Some(overdraw);
+        }
+    }
+
+    // Writes a shaded color, blending with what is already there for
+    // translucent materials.
+    fn put_color(&mut self, x: usize, y: usize, c: Color) {
+        let mat = match self.material {
+            Some(mat) => { mat }
+            None => {
+                self.write_color(x, y, c);
+                return;
+            }
+        };
+        let dst = self.read_color(x, y);
+        let out = match mat.blend {
+            BlendMode::Opaque => { c }
+            BlendMode::AlphaBlend => { dst.lerp(c, mat.alpha) }
+            BlendMode::Additive => { dst + c * mat.alpha }
+        };
+        self.write_color(x, y, out);
     }
+
     //渲染部分
     pub fn draw_scanline(&mut self, mut scanline: Scanline) {
         let mut x = scanline.x;
         let y = scanline.y as usize;
         let mut w = scanline.w;
+        let width = self.zbuffer.width as i32;
 
         let render_state = self.render_state;
+        let shaded = !self.unlit && (!self.lights.is_empty() || self.material.is_some());
+        let write_depth = self.material.is_none_or(|m| m.blend == BlendMode::Opaque);
+        let diffuse_map = self.material.and_then(|m| m.diffuse_map);
         while w > 0 {
-            if x >= 0 && x < HEIGHT as i32 {
+            if x >= 0 && x < width {
                 let rhw = scanline.v.rhw;
                 let w1 = 1. / rhw;
 
-                if rhw >= self.zbuffer[y as usize][x as usize] as f32 {
-                    self.zbuffer[y as usize][x as usize] = rhw;
-                    if render_state & RENDER_STATE_COLOR > 0 {
-                        let R = scanline.v.color.r * w1;
-                        let G = scanline.v.color.g * w1;
-                        let B = scanline.v.color.b * w1;
-                        let mut r = (R * 255. as f32) as i32;
-                        let mut g = (G * 255. as f32) as i32;
-                        let mut b = (B * 255. as f32) as i32;
-                        r = CMID(r, 0, 255);
-                        g= CMID(g, 0, 255);
-                        b = CMID(b, 0, 255);
-                        self.framebuf[y * WIDTH + x as usize] = ((r << 16) | (g << 8) | (b)) as u32;
+                if rhw >= self.zbuffer[(x as usize, y)] {
+                    if write_depth {
+                        self.zbuffer[(x as usize, y)] = rhw;
+                        if let Some(normals) = self.normal_buffer.as_mut() {
+                            normals[(x as usize, y)] = (scanline.v.normal * w1).normalized().xyz();
+                        }
+                        // the outline hull is no pickable geometry
+                        if let Some(ids) = self.id_buffer.as_mut().filter(|_| !self.cull_front && !self.unlit) {
+                            let barycentric = screen_barycentric(&self.pick_tri, Vector2::new(x as f32 + 0.5, y as f32 + 0.5));
+                            let id = PickResult { object: self.object_id, triangle: self.triangle_id, depth: w1, barycentric };
+                            ids[(x as usize, y)] = Some(id);
+                        }
+                    }
+                    if let Some(overdraw) = self.overdraw.as_mut() {
+                        overdraw[(x as usize, y)] += 1;
+                    }
+                    if render_state & RENDER_STATE_DEBUG > 0 {
+                        if let Some(c) = self.debug_color(&scanline.v, w1) {
+                            self.write_debug(x as usize, y, c);
+                        }
+                    } else if render_state & RENDER_STATE_COLOR > 0 {
+                        let mut c = Color {
+                            r: scanline.v.color.r * w1,
+                            g: scanline.v.color.g * w1,
+                            b: scanline.v.color.b * w1,
+                        };
+                        if shaded {
+                            c = self.shade(c, &scanline.v, w1);
+                        }
+                        // w is view-space depth for the perspective projection
+                        if let Some(fog) = self.fog {
+                            c = fog.apply(c, w1);
+                        }
+                        self.put_color(x as usize, y, c);
                     }
-                    if render_state & RENDER_STATE_TEXTURE > 0 {
+                    if render_state & RENDER_STATE_TEXTURE > 0 && render_state & RENDER_STATE_DEBUG == 0 {
                         let u = scanline.v.tc.u * w1;
                         let v = scanline.v.tc.v * w1;
-                        self.framebuf[y * WIDTH + x as usize] = self.texture_read(u, v);
+                        let footprint = self.uv_footprint(&scanline.v, w1);
+                        let texel = self.sample_color(diffuse_map, u, v, footprint)
+                            .or_else(|| self.sample_color(self.texture, u, v, footprint))
+                            .unwrap_or_default();
+                        let mut c = match shaded {
+                            true => { self.shade(texel, &scanline.v, w1) }
+                            false => { texel }
+                        };
+                        if let Some(fog) = self.fog {
+                            c = fog.apply(c, w1);
+                        }
+                        self.put_color(x as usize, y, c);
                     }
                 }
             }
             scanline.v.add(scanline.step);
-            if x >= HEIGHT as i32 {
+            if x >= width {
                 break;
             }
             w -= 1;
@@ -286,33 +825,8 @@ impl Device {
     }
 
     pub fn render_trap(&mut self, trap: &mut Trapezoid) {
-        let mut scanline = Scanline {
-            v: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            },
-            step: Vertex {
-                pos: Vector4f { x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                tc: Texcoord { u: 0.0, v: 0.0 },
-                color: Color { r: 0.0, g: 0.0, b: 0.0 },
-                rhw: 0.0
-            }, x: 0, y: 0, w: 0
-        };
-        let top = (trap.top + 0.5) as i32;
-        let bottom = (trap.bottom + 0.5) as i32;
-        //println!("{} {}", top, bottom);
-        for j in top..bottom {
-            if j >= 0 && j < HEIGHT as i32 {
-                trapezoid_edge_interp(trap, (j as f32 + 0.5));
-                trapezoid_init_scan_line(trap, &mut scanline, j);
-                self.draw_scanline(scanline);
-            }
-            if j >= HEIGHT as i32 {
-                break;
-            }
-        }
+        let height = self.zbuffer.height as i32;
+        trapezoid_scan(trap, height, |scanline| self.draw_scanline(scanline));
     }
 
     pub fn draw_primitive(&mut self, v1: &mut Vertex, v2: &mut Vertex, v3: &mut Vertex) {
@@ -339,10 +853,37 @@ impl Device {
         self.transform.homogenize(&mut p1, c1);
         self.transform.homogenize(&mut p2, c2);
         self.transform.homogenize(&mut p3, c3);
-        if (render_state & (RENDER_STATE_TEXTURE | RENDER_STATE_COLOR)) > 0 {
+        // Front faces (counter-clockwise seen from outside) have positive area
+        // here: the left-handed lookat and the flipped screen y cancel out.
+        let area = (p2.x - p1.x) * (p3.y - p1.y) - (p3.x - p1.x) * (p2.y - p1.y);
+        self.back_facing = area < 0.0;
+        if self.cull_front {
+            if !self.back_facing {
+                return;
+            }
+        } else if self.back_facing && self.material.is_some_and(|m| !m.double_sided) {
+            return;
+        }
+        self.primitive_id += 1;
+        self.pick_tri = [
+            Vector4f { w: c1.w, ..p1 },
+            Vector4f { w: c2.w, ..p2 },
+            Vector4f { w: c3.w, ..p3 },
+        ];
+        if (render_state & (RENDER_STATE_TEXTURE | RENDER_STATE_COLOR | RENDER_STATE_DEBUG)) > 0 {
             let mut t1 = *v1;
             let mut t2 = *v2;
             let mut t3 = *v3;
+            for t in [&mut t1, &mut t2, &mut t3] {
+                t.world = t.pos * self.transform.world;
+                t.normal = t.normal * self.transform.normal;
+                let sign = t.tangent.w;
+                t.tangent = Vector4f { w: 0.0, ..t.tangent } * self.transform.world;
+                t.tangent.w = sign;
+                if self.srgb {
+                    t.color = srgb_decode(t.color);
+                }
+            }
             let traps: &mut [Trapezoid; 2] = &mut [trapezoid_init(); 2];
             t1.pos = p1.clone();
             t2.pos = p2.clone();
@@ -354,6 +895,7 @@ impl Device {
             t1.rhw_init();
             t2.rhw_init();
             t3.rhw_init();
+            self.uv_grad = screen_gradients(&t1, &t2, &t3);
             let mut n = 0;
             unsafe {
                 n = trapezoid_init_triangle(traps, t1, t2, t3);
@@ -409,6 +951,272 @@ impl Device {
         self.draw_plane(3, 7, 4, 0);
     }
 
+    // Whole-mesh frustum test in object space, before any vertex is transformed.
+    pub fn is_mesh_visible(&self, mesh: &Mesh) -> bool {
+        if !self.culling || mesh.aabb.is_empty() {
+            return true;
+        }
+        let frustum = Frustum::from_matrix(self.transform.transform);
+        frustum.intersects_sphere(&mesh.sphere) && frustum.intersects_aabb(&mesh.aabb)
+    }
+
+    // Binds each triangle's material from `materials` (Scene::materials)
+    // before drawing it; with no materials the legacy unshaded path is used.
+    pub fn draw_mesh(&mut self, mesh: &Mesh, materials: &[Material]) {
+        if !self.is_mesh_visible(mesh) {
+            self.stats.objects_culled += 1;
+            self.stats.triangles_culled += mesh.triangle_count();
+            return;
+        }
+        self.stats.objects_drawn += 1;
+        self.draw_triangles(mesh, materials, None);
+        if self.render_state & RENDER_STATE_OUTLINE_HULL > 0 && mesh.blend_counts(materials).1 == 0 {
+            self.draw_outline_hull(mesh);
+        }
+    }
+
+    // The triangles of a visible mesh whose material is blended (Some(true))
+    // or opaque (Some(false)), or all of them for None.
+    fn draw_triangles(&mut self, mesh: &Mesh, materials: &[Material], blended: Option<bool>) {
+        let mut drawn = 0;
+        // with a BVH only the triangles in leaves overlapping the frustum
+        match (self.culling, mesh.bvh.as_ref()) {
+            (true, Some(bvh)) => {
+                let frustum = Frustum::from_matrix(self.transform.transform);
+                let mut visible = std::mem::take(&mut self.visible_triangles);
+                bvh.frustum_triangles(&frustum, &mut visible);
+                for &t in visible.iter() {
+                    drawn += self.draw_mesh_triangle(mesh, materials, t as usize, blended) as usize;
+                }
+                self.visible_triangles = visible;
+            }
+            _ => {
+                for i in 0..mesh.triangle_count() {
+                    drawn += self.draw_mesh_triangle(mesh, materials, i, blended) as usize;
+                }
+            }
+        }
+        self.material = None;
+        let total = match blended {
+            Some(true) => { mesh.blend_counts(materials).1 }
+            Some(false) => { mesh.blend_counts(materials).0 }
+            None => { mesh.triangle_count() }
+        };
+        self.stats.triangles_drawn += drawn;
+        self.stats.triangles_culled += total - drawn;
+    }
+
+    // Draws triangle i with its material bound; false when the material is
+    // not in the requested pass.
+    fn draw_mesh_triangle(&mut self, mesh: &Mesh, materials: &[Material], i: usize, blended: Option<bool>) -> bool {
+        self.material = mesh.material_of(i).and_then(|m| materials.get(m)).copied();
+        let is_blended = self.material.is_some_and(|m| m.blend != BlendMode::Opaque);
+        if blended.is_some_and(|b| b != is_blended) {
+            return false;
+        }
+        self.triangle_id = i as u32;
+        let (mut p1, mut p2, mut p3) = mesh.triangle(i);
+        self.draw_primitive(&mut p1, &mut p2, &mut p3);
+        true
+    }
+
+    // The mesh pushed out along its normals and drawn back faces only in
+    // outline_color; only the rim around the silhouette survives the depth test.
+    // Normals are averaged over vertices sharing a position so hard edges
+    // don't tear the hull open.
+    fn draw_outline_hull(&mut self, mesh: &Mesh) {
+        let key = |p: Vector4f| ((p.x * 1e4) as i64, (p.y * 1e4) as i64, (p.z * 1e4) as i64);
+        let mut smooth: HashMap<(i64, i64, i64), Vector4f> = HashMap::new();
+        for v in mesh.vertices.iter() {
+            let sum = smooth.entry(key(v.pos)).or_insert(Vector4f::direction(0.0, 0.0, 0.0));
+            *sum = *sum + Vector4f { w: 0.0, ..v.normal }.normalized();
+        }
+        let render_state = self.render_state;
+        self.render_state = RENDER_STATE_COLOR;
+        self.cull_front = true;
+        self.unlit = true;
+        for i in 0..mesh.triangle_count() {
+            let (mut p1, mut p2, mut p3) = mesh.triangle(i);
+            for v in [&mut p1, &mut p2, &mut p3] {
+                let n = smooth[&key(v.pos)].normalized();
+                v.pos = v.pos + n * self.outline_width;
+                v.color = self.outline_color;
+            }
+            self.draw_primitive(&mut p1, &mut p2, &mut p3);
+        }
+        self.render_state = render_state;
+        self.cull_front = false;
+        self.unlit = false;
+    }
+
+    // Marks pixels where view depth jumps or the normal turns sharply
+    // against the right or lower neighbour. Needs RENDER_STATE_OUTLINE_EDGE
+    // set before clear() so the normal buffer gets filled.
+    pub fn draw_edge_outlines(&mut self) {
+        let normals = match self.normal_buffer.take() {
+            Some(normals) => { normals }
+            None => { return; }
+        };
+        let (w, h) = (self.zbuffer.width, self.zbuffer.height);
+        let depth = |rhw: f32| match rhw > 0.0 {
+            true => { 1.0 / rhw }
+            false => { f32::INFINITY }
+        };
+        for y in 0..h {
+            for x in 0..w {
+                let d0 = depth(self.zbuffer[(x, y)]);
+                let n0 = normals[(x, y)];
+                let mut edge = false;
+                for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                    if nx >= w || ny >= h {
+                        continue;
+                    }
+                    let d1 = depth(self.zbuffer[(nx, ny)]);
+                    if d0.is_infinite() && d1.is_infinite() {
+                        continue;
+                    }
+                    let jump = (d0 - d1).abs() / d0.min(d1);
+                    if jump > self.outline_depth || n0.dot(normals[(nx, ny)]) < self.outline_normal {
+                        edge = true;
+                    }
+                }
+                if edge {
+                    self.write_color(x, y, self.outline_color);
+                }
+            }
+        }
+        self.normal_buffer = Some(normals);
+    }
+
+    // Expects scene.update_world() to have been called this frame. Opaque
+    // triangles go first so blended ones composite over finished depth;
+    // meshes with blended submeshes are then drawn back to front.
+    pub fn draw_scene(&mut self, scene: &Scene) {
+        self.lights = scene.lights();
+        self.render_shadows(scene);
+        let mut blended: Vec<(f32, usize, usize)> = Vec::new();
+        for (node, mesh) in scene.drawables() {
+            let m = &scene.meshes[mesh];
+            self.transform.world = scene.nodes[node].world;
+            self.transform.update();
+            if !self.is_mesh_visible(m) {
+                self.stats.objects_culled += 1;
+                self.stats.triangles_culled += m.triangle_count();
+                continue;
+            }
+            self.stats.objects_drawn += 1;
+            let (opaque, blend) = m.blend_counts(&scene.materials);
+            if opaque > 0 {
+                self.object_id = node as u32;
+                self.draw_triangles(m, &scene.materials, Some(false));
+                if self.render_state & RENDER_STATE_OUTLINE_HULL > 0 && blend == 0 {
+                    self.draw_outline_hull(m);
+                }
+            }
+            if blend > 0 {
+                let center = m.sphere.center.to_point() * self.transform.world * self.transform.view;
+                blended.push((center.z, node, mesh));
+            }
+        }
+        blended.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (_, node, mesh) in blended {
+            self.transform.world = scene.nodes[node].world;
+            self.transform.update();
+            self.object_id = node as u32;
+            self.draw_triangles(&scene.meshes[mesh], &scene.materials, Some(true));
+        }
+        if self.render_state & RENDER_STATE_OUTLINE_EDGE > 0 {
+            self.draw_edge_outlines();
+        }
+        if self.render_state & (RENDER_STATE_DEBUG_DEPTH | RENDER_STATE_DEBUG_OVERDRAW) > 0 {
+            self.draw_debug_buffers();
+        }
+    }
+
+    // Allocates or drops the per-pixel ID buffer read by pick().
+    pub fn set_id_buffer(&mut self, on: bool) {
+        self.id_buffer = match on {
+            true => { Some(Buffer2D::new(self.zbuffer.width, self.zbuffer.height, None)) }
+            false => { None }
+        };
+    }
+
+    // What the last frame drew at pixel (x, y) (opaque geometry only). None
+    // for background or when the ID buffer is off.
+    pub fn pick(&self, x: usize, y: usize) -> Option<PickResult> {
+        self.id_buffer.as_ref().and_then(|ids| ids.get(x, y)).flatten()
+    }
+
+    // Ray-cast version of pick() that needs no ID buffer: each visible mesh
+    // is hit-tested through the inverse of its full transform. Slower, but
+    // also sees blended geometry.
+    pub fn pick_raycast(&self, scene: &Scene, x: usize, y: usize) -> Option<PickResult> {
+        let (w, h) = (self.transform.w, self.transform.h);
+        let (sx, sy) = (x as f32 + 0.5, y as f32 + 0.5);
+        let mut best: Option<PickResult> = None;
+        for (node, mesh) in scene.drawables() {
+            let mut t = self.transform;
+            t.world = scene.nodes[node].world;
+            t.update();
+            let mesh = &scene.meshes[mesh];
+            if let Some((triangle, _, barycentric)) = raycast_mesh(mesh, t.transform, w, h, sx, sy) {
+                let (a, b, c) = mesh.triangle(triangle as usize);
+                let p = a.pos * barycentric.x + b.pos * barycentric.y + c.pos * barycentric.z;
+                let depth = (p * t.transform).w;
+                if depth > 0.0 && best.is_none_or(|b| depth < b.depth) {
+                    best = Some(PickResult { object: node as u32, triangle, depth, barycentric });
+                }
+            }
+        }
+        best
+    }
+
+    // Depth pass from every shadow-casting light over all visible meshes.
+    pub fn render_shadows(&mut self, scene: &Scene) {
+        let drawables = scene.drawables();
+        let mut bounds = Aabb::empty();
+        for &(node, mesh) in drawables.iter() {
+            bounds = bounds.merge(scene.meshes[mesh].aabb.transformed(scene.nodes[node].world));
+        }
+        let mut maps = std::mem::take(&mut self.shadow_maps);
+        maps.resize_with(self.lights.len(), || None);
+        for (i, light) in self.lights.iter().enumerate() {
+            if !light.cast_shadows {
+                maps[i] = None;
+                continue;
+            }
+            if maps[i].as_ref().is_none_or(|m| m.size != self.shadow_map_size) {
+                maps[i] = Some(ShadowMap::new(self.shadow_map_size));
+            }
+            let map = maps[i].as_mut().unwrap();
+            if !map.setup(light, bounds) {
+                maps[i] = None;
+                continue;
+            }
+            map.clear();
+            for &(node, mesh) in drawables.iter() {
+                map.render_mesh(&scene.meshes[mesh], scene.nodes[node].world);
+            }
+        }
+        self.shadow_maps = maps;
+    }
+
+    // Debug view: light i's shadow map as grayscale in a size x size square,
+    // nearer surfaces brighter.
+    pub fn draw_shadow_map(&mut self, i: usize, x0: usize, y0: usize, size: usize) {
+        let map = match self.shadow_maps.get(i) {
+            Some(Some(map)) => { map }
+            _ => { return; }
+        };
+        let mut view = self.framebuf.view_mut(x0, y0, size, size);
+        for y in 0..view.height {
+            for x in 0..view.width {
+                let d = map.depth[(x * map.size / size, y * map.size / size)];
+                view.set(x, y, Color::new(1.0 - d, 1.0 - d, 1.0 - d).to_u32());
+            }
+        }
+    }
+
     pub fn camera_at_zero(&mut self, x: f32, y: f32, z: f32) {
         let eye = Vector4f {
             x,
@@ -432,17 +1240,120 @@ impl Device {
         self.transform.update();
     }
 
-    pub fn init_texture(&mut self) {
-        for j in 0..256 {
-            for i in 0..256 {
-                let x = i / 32;
-                let y = j / 32;
-                self.texture[j][i] = match (x + y) & 1 > 0 {
-                    true => { 0xffffff }
-                    false => { 0x3fbcef }
-                };
+    // Rounded bumps, one per checker cell of init_texture.
+    pub fn init_normal_map(&mut self) -> TextureHandle {
+        let mut height = vec![vec![0.0; 256]; 256];
+        for (j, row) in height.iter_mut().enumerate() {
+            for (i, h) in row.iter_mut().enumerate() {
+                let x = (i % 32) as f32 / 16.0 - 1.0;
+                let y = (j % 32) as f32 / 16.0 - 1.0;
+                *h = (1.0 - x * x - y * y).max(0.0).sqrt() * 8.0;
             }
         }
-        self.set_texture(256, 256);
+        if let Some(old) = self.normal_map.take() {
+            self.textures.remove(old);
+        }
+        let mut map = normal_map_from_height(&height, 1.0);
+        map.address = TextureAddress::Repeat;
+        let handle = self.textures.insert(map);
+        self.normal_map = Some(handle);
+        handle
+    }
+
+    pub fn init_texture(&mut self) -> TextureHandle {
+        if let Some(old) = self.texture.take() {
+            self.textures.remove(old);
+        }
+        let mut checker = Texture::checkerboard(256, 32, 0xffffff, 0x3fbcef);
+        checker.generate_mips();
+        let handle = self.textures.insert(checker);
+        self.set_texture(handle);
+        handle
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Device, RENDER_STATE_COLOR};
+    use crate::calc::Color;
+    use crate::material::{BlendMode, Material};
+    use crate::mesh::Mesh;
+    use crate::quaternion_calc::Quaternion;
+    use crate::scene::{Node, Scene};
+    use crate::vector_calc::Vector4f;
+
+    // A white 2x2 quad at height x facing the camera on +X, in material mat.
+    fn add_quad(scene: &mut Scene, x: f32, mat: Material) {
+        let mut mesh = Mesh::plane(2.0, 2.0, 1, 1);
+        for v in mesh.vertices.iter_mut() {
+            v.color = Color::new(1.0, 1.0, 1.0);
+        }
+        mesh.material = Some(scene.add_material(mat));
+        let mesh = scene.add_mesh(mesh);
+        let mut node = Node::new("quad");
+        node.mesh = Some(mesh);
+        node.translation = Vector4f::point(x, 0.0, 0.0);
+        node.rotation = Quaternion::from_axis_angle(Vector4f::direction(0.0, 1.0, 0.0), std::f32::consts::FRAC_PI_2);
+        scene.add_node(node, None);
+    }
+
+    fn blended(color: Color, alpha: f32, blend: BlendMode) -> Material {
+        let mut mat = Material::new();
+        mat.base_color = color;
+        mat.alpha = alpha;
+        mat.blend = blend;
+        mat.double_sided = true;
+        mat
+    }
+
+    // Linear color at the center of the frame after drawing scene.
+    fn center(scene: &mut Scene) -> Color {
+        let mut device = Device::init(32, 32);
+        device.render_state = RENDER_STATE_COLOR;
+        device.set_hdr(true);
+        device.clear(0);
+        device.camera_at_zero(4.0, 0.0, 0.0);
+        scene.update_world();
+        device.draw_scene(scene);
+        device.hdr.as_ref().unwrap()[(16, 16)]
+    }
+
+    fn assert_near(a: Color, b: Color) {
+        assert!((a.r - b.r).abs() < 1e-3 && (a.g - b.g).abs() < 1e-3 && (a.b - b.b).abs() < 1e-3, "{:?} vs {:?}", a, b);
+    }
+
+    #[test]
+    fn blended_quads_composite_back_to_front() {
+        let gray = blended(Color::new(0.2, 0.2, 0.2), 1.0, BlendMode::Opaque);
+        let red = blended(Color::new(1.0, 0.0, 0.0), 0.5, BlendMode::AlphaBlend);
+        let green = blended(Color::new(0.0, 1.0, 0.0), 0.5, BlendMode::Additive);
+        // nearest first, so scene order is the wrong order to draw in
+        let mut scene = Scene::new();
+        add_quad(&mut scene, 0.5, green);
+        add_quad(&mut scene, 0.0, red);
+        add_quad(&mut scene, -1.0, gray);
+        // red over gray at half alpha, then half of green added on top
+        assert_near(center(&mut scene), Color::new(0.6, 0.6, 0.1));
+
+        let blue = blended(Color::new(0.0, 0.0, 1.0), 0.5, BlendMode::AlphaBlend);
+        let mut scene = Scene::new();
+        add_quad(&mut scene, 0.5, blue);
+        add_quad(&mut scene, 0.0, red);
+        add_quad(&mut scene, -1.0, gray);
+        assert_near(center(&mut scene), Color::new(0.3, 0.05, 0.55));
+    }
+
+    #[test]
+    fn additive_quads_accumulate_behind_opaque() {
+        // half of red twice over black
+        let red = blended(Color::new(1.0, 0.0, 0.0), 0.5, BlendMode::Additive);
+        let mut scene = Scene::new();
+        add_quad(&mut scene, 0.5, red);
+        add_quad(&mut scene, 0.0, red);
+        assert_near(center(&mut scene), Color::new(1.0, 0.0, 0.0));
+        // opaque geometry in front hides both
+        let gray = blended(Color::new(0.2, 0.2, 0.2), 1.0, BlendMode::Opaque);
+        add_quad(&mut scene, 1.0, gray);
+        assert_near(center(&mut scene), Color::new(0.2, 0.2, 0.2));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::calc::Color;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FogMode {
+    Linear, // ramps from start to end
+    Exp,    // 1 - e^(-density * d)
+    Exp2,   // 1 - e^(-(density * d)^2)
+}
+
+// Distance fog over view-space depth. color is in the shading space, so
+// linear when the sRGB pipeline is on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fog {
+    pub mode: FogMode,
+    pub color: Color,
+    pub start: f32,
+    pub end: f32,
+    pub density: f32,
+}
+
+impl Fog {
+    pub fn linear(color: Color, start: f32, end: f32) -> Fog {
+        Fog { mode: FogMode::Linear, color, start, end, density: 0.0 }
+    }
+
+    pub fn exp(color: Color, density: f32) -> Fog {
+        Fog { mode: FogMode::Exp, color, start: 0.0, end: 0.0, density }
+    }
+
+    pub fn exp2(color: Color, density: f32) -> Fog {
+        Fog { mode: FogMode::Exp2, color, start: 0.0, end: 0.0, density }
+    }
+
+    // How much fog covers a surface at view depth d, 0..1. The exponential
+    // modes also honour start, measuring d from there.
+    pub fn amount(&self, depth: f32) -> f32 {
+        let f = match self.mode {
+            FogMode::Linear => {
+                match self.end > self.start {
+                    true => { (depth - self.start) / (self.end - self.start) }
+                    false if depth >= self.start => { 1.0 }
+                    false => { 0.0 }
+                }
+            }
+            FogMode::Exp => {
+                let d = (depth - self.start).max(0.0);
+                1.0 - (-self.density * d).exp()
+            }
+            FogMode::Exp2 => {
+                let d = (depth - self.start).max(0.0) * self.density;
+                1.0 - (-d * d).exp()
+            }
+        };
+        f.clamp(0.0, 1.0)
+    }
+
+    pub fn apply(&self, c: Color, depth: f32) -> Color {
+        c.lerp(self.color, self.amount(depth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fog;
+    use crate::calc::Color;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-6, "{} vs {}", a, b);
+    }
+
+    #[test]
+    fn linear_ramps_between_start_and_end() {
+        let fog = Fog::linear(Color::new(1.0, 1.0, 1.0), 2.0, 6.0);
+        assert_near(fog.amount(2.0), 0.0);
+        assert_near(fog.amount(3.0), 0.25);
+        assert_near(fog.amount(4.0), 0.5);
+        assert_near(fog.amount(6.0), 1.0);
+        // clamped outside the ramp
+        assert_near(fog.amount(0.5), 0.0);
+        assert_near(fog.amount(-3.0), 0.0);
+        assert_near(fog.amount(100.0), 1.0);
+        // an empty ramp is a hard wall at start
+        let wall = Fog::linear(Color::new(1.0, 1.0, 1.0), 5.0, 5.0);
+        assert_near(wall.amount(4.9), 0.0);
+        assert_near(wall.amount(5.0), 1.0);
+        let backwards = Fog::linear(Color::new(1.0, 1.0, 1.0), 5.0, 1.0);
+        assert_near(backwards.amount(3.0), 0.0);
+        assert_near(backwards.amount(7.0), 1.0);
+    }
+
+    #[test]
+    fn exp_modes_at_known_depths() {
+        let exp = Fog::exp(Color::new(1.0, 1.0, 1.0), 0.5);
+        assert_near(exp.amount(0.0), 0.0);
+        assert_near(exp.amount(2.0), 1.0 - (-1.0f32).exp());
+        assert_near(exp.amount(4.0), 1.0 - (-2.0f32).exp());
+        let exp2 = Fog::exp2(Color::new(1.0, 1.0, 1.0), 0.5);
+        assert_near(exp2.amount(2.0), 1.0 - (-1.0f32).exp());
+        assert_near(exp2.amount(4.0), 1.0 - (-4.0f32).exp());
+        // thinner than exp before density * d = 1, thicker after
+        assert!(exp2.amount(1.0) < exp.amount(1.0) && exp2.amount(3.0) > exp.amount(3.0));
+        for fog in [exp, exp2].iter() {
+            assert_near(fog.amount(-5.0), 0.0);
+            assert!(fog.amount(1e6) <= 1.0);
+            assert!(fog.amount(1e6) > 0.999);
+        }
+    }
+
+    #[test]
+    fn exp_modes_measure_from_start() {
+        let mut exp = Fog::exp(Color::new(1.0, 1.0, 1.0), 0.5);
+        exp.start = 3.0;
+        assert_near(exp.amount(2.0), 0.0);
+        assert_near(exp.amount(5.0), 1.0 - (-1.0f32).exp());
+        let mut exp2 = Fog::exp2(Color::new(1.0, 1.0, 1.0), 0.5);
+        exp2.start = 3.0;
+        assert_near(exp2.amount(3.0), 0.0);
+        assert_near(exp2.amount(5.0), 1.0 - (-1.0f32).exp());
+    }
+
+    #[test]
+    fn apply_blends_towards_fog_color() {
+        let fog = Fog::linear(Color::new(1.0, 0.5, 0.0), 0.0, 4.0);
+        let c = fog.apply(Color::new(0.0, 0.5, 1.0), 1.0);
+        assert_near(c.r, 0.25);
+        assert_near(c.g, 0.5);
+        assert_near(c.b, 0.75);
+        assert_eq!(fog.apply(Color::new(0.0, 0.5, 1.0), 9.0), fog.color);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::calc::Color;
+use crate::vector_calc::Vector4f;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point,
+    Spot,
+}
+
+// Lights shine along their local +Z axis. position and direction are in world
+// space once Scene::lights() has resolved them from the owning node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: Color,
+    pub intensity: f32,
+    pub position: Vector4f,
+    pub direction: Vector4f,
+    pub range: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub cast_shadows: bool,
+}
+
+impl Light {
+    pub fn directional(color: Color, intensity: f32) -> Light {
+        Light {
+            kind: LightKind::Directional,
+            color,
+            intensity,
+            position: Vector4f::point(0.0, 0.0, 0.0),
+            direction: Vector4f::direction(0.0, 0.0, 1.0),
+            range: 0.0,
+            inner_angle: 0.0,
+            outer_angle: 0.0,
+            cast_shadows: false,
+        }
+    }
+
+    pub fn point(color: Color, intensity: f32, range: f32) -> Light {
+        Light {
+            kind: LightKind::Point,
+            range,
+            ..Light::directional(color, intensity)
+        }
+    }
+
+    // Angles are half-angles of the cone in radians.
+    pub fn spot(color: Color, intensity: f32, range: f32, inner_angle: f32, outer_angle: f32) -> Light {
+        Light {
+            kind: LightKind::Spot,
+            range,
+            inner_angle,
+            outer_angle,
+            ..Light::directional(color, intensity)
+        }
+    }
+}
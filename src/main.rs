@@ -3,25 +3,81 @@ mod vector_calc;
 mod transform_calc;
 mod calc;
 mod matrix_calc;
+mod quaternion_calc;
 mod vertex;
 mod screen;
+mod bounds;
+mod cubemap;
+mod buffer;
+mod bvh;
+mod fog;
+mod light;
+mod texture;
+mod tonemap;
+mod material;
+mod mesh;
+mod pathtrace;
+mod pixel;
+mod pick;
+mod postprocess;
+mod primitive;
+mod ray;
+mod scene;
+mod shading;
+mod shadow;
 
 use std::thread::sleep;
 use std::time::Duration;
-use minifb::{InputCallback, Key, Menu, Scale, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Scale, Window, WindowOptions};
 use minifb::Key::K;
-use crate::calc::swap;
+use crate::calc::Color;
+use crate::cubemap::Cubemap;
 use crate::device::Device;
+use crate::fog::{Fog, FogMode};
+use crate::light::Light;
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::pathtrace::PathTracer;
+use crate::pixel::PixelFormat;
+use crate::postprocess::{ColorLut, PostPass};
+use crate::quaternion_calc::Quaternion;
+use crate::scene::{Node, Scene};
+use crate::vector_calc::{Vector3, Vector4f};
 
 const WIDTH: usize = 800;
 const HEIGHT: usize = 600;
 const RENDER_STATE_WIREFRAME: i32 = 1;
 const RENDER_STATE_TEXTURE: i32 = 2;
 const RENDER_STATE_COLOR: i32 = 4;
+const RENDER_STATE_TOON: i32 = 8;
+const RENDER_STATE_OUTLINE_HULL: i32 = 16;
+const RENDER_STATE_OUTLINE_EDGE: i32 = 32;
+const RENDER_STATE_DEBUG_DEPTH: i32 = 64;
+const RENDER_STATE_DEBUG_NORMALS: i32 = 128;
+const RENDER_STATE_DEBUG_VIEW_NORMALS: i32 = 256;
+const RENDER_STATE_DEBUG_UV: i32 = 512;
+const RENDER_STATE_DEBUG_OVERDRAW: i32 = 1024;
+const RENDER_STATE_DEBUG_TRIANGLES: i32 = 2048;
 
 fn main() {
-    let mut device = Device::init("owo", WIDTH, HEIGHT);
-    let arr = [RENDER_STATE_WIREFRAME, RENDER_STATE_TEXTURE, RENDER_STATE_COLOR];
+    let mut device = Device::init(WIDTH, HEIGHT);
+    let mut window = Window::new("owo", WIDTH, HEIGHT, WindowOptions {
+        scale: Scale::X1,
+        ..WindowOptions::default()
+    }).unwrap();
+    let arr = [
+        RENDER_STATE_WIREFRAME,
+        RENDER_STATE_TEXTURE,
+        RENDER_STATE_COLOR,
+        RENDER_STATE_COLOR | RENDER_STATE_TOON | RENDER_STATE_OUTLINE_HULL,
+        RENDER_STATE_TEXTURE | RENDER_STATE_TOON | RENDER_STATE_OUTLINE_EDGE,
+        RENDER_STATE_DEBUG_DEPTH,
+        RENDER_STATE_DEBUG_NORMALS,
+        RENDER_STATE_DEBUG_VIEW_NORMALS,
+        RENDER_STATE_DEBUG_UV,
+        RENDER_STATE_DEBUG_OVERDRAW,
+        RENDER_STATE_DEBUG_TRIANGLES,
+    ];
     device.init_texture();
     device.camera_at_zero(3., 0., 0.);
     device.render_state = RENDER_STATE_WIREFRAME;
@@ -29,30 +85,91 @@ fn main() {
     let mut pos = 3.5;
     let mut alpha = 1.;
 
+    let mut scene = Scene::new();
+    let mut shiny = Material::new();
+    shiny.specular = Color::new(0.6, 0.6, 0.6);
+    shiny.shininess = 48.0;
+    shiny.reflectivity = 0.25; // shows once the skybox is on
+    let shiny = scene.add_material(shiny);
+    let mut cube = Mesh::cube();
+    cube.material = Some(shiny);
+    let cube = scene.add_mesh(cube);
+    let mut node = Node::new("box");
+    node.mesh = Some(cube);
+    let root = scene.add_node(node, None);
+    let mut node = Node::new("satellite");
+    node.mesh = Some(cube);
+    node.translation = Vector4f::point(0.0, 0.0, 1.8);
+    node.scale = Vector3::new(0.3, 0.3, 0.3);
+    let satellite = scene.add_node(node, Some(root));
+    let floor = scene.add_mesh(Mesh::grid(10.0, 10, Color::new(0.9, 0.9, 0.9), Color::new(0.5, 0.5, 0.5)));
+    let mut node = Node::new("floor");
+    node.mesh = Some(floor);
+    node.translation = Vector4f::point(0.0, 0.0, -2.2);
+    scene.add_node(node, None);
+    let mut sun = Light::directional(Color::new(1.0, 1.0, 1.0), 0.9);
+    sun.cast_shadows = true;
+    let mut node = Node::new("sun");
+    node.light = Some(sun);
+    node.rotation = Quaternion::look_rotation(Vector4f::direction(-0.5, 0.6, -1.0), Vector4f::direction(0.0, 1.0, 0.0));
+    scene.add_node(node, None);
+    let mut show_shadow_map = false;
+    let sky = Cubemap::from_fn(128, |d| {
+        let horizon = Color::new(0.85, 0.85, 0.8);
+        match d.z >= 0.0 {
+            true => { horizon.lerp(Color::new(0.25, 0.45, 0.85), d.z.sqrt()) }
+            false => { horizon.lerp(Color::new(0.3, 0.27, 0.25), (-d.z).sqrt()) }
+        }
+    });
+    let mut sky = Some(sky);
+    // F1..F7 toggle these
+    let post_passes = [
+        PostPass::Fxaa { span_max: 8.0, reduce_mul: 1.0 / 8.0, reduce_min: 1.0 / 128.0 },
+        PostPass::Bloom { threshold: 0.8, intensity: 0.6, radius: 4 },
+        PostPass::Vignette { strength: 0.6, radius: 0.5 },
+        PostPass::ColorGrade { lut: ColorLut::from_fn(16, |c| Color::new(c.r * 1.1, c.g, c.b * 0.85)), strength: 1.0 },
+        PostPass::Sharpen { amount: 0.5 },
+        PostPass::Grayscale { amount: 1.0 },
+        PostPass::DepthOfField { focus: 3.5, range: 3.0, radius: 3 },
+    ];
+    for pass in post_passes {
+        device.post.push(pass, false);
+    }
+    let post_keys = [Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7];
+
+    device.set_id_buffer(true);
+    let mut mouse_was_down = false;
+    // window title: last pick result and this frame's culling counters
+    let mut picked = String::from("owo");
+    let mut title = String::new();
+    // P swaps the rasterizer for the progressive path tracer
+    let mut tracer: Option<PathTracer> = None;
+
     let mut kbhit = 0;
     let mut indicator = 0;
 
-    while device.window.is_open() && !device.window.is_key_down(Key::Escape) {
+    while window.is_open() && !window.is_key_down(Key::Escape) {
         device.clear(1);
         device.camera_at_zero(pos, 0., 0.);
-        if device.window.is_key_down(Key::Up) {
+        device.draw_skybox();
+        if window.is_key_down(Key::Up) {
             pos -= 0.1;
         }
-        if device.window.is_key_down(Key::Down) {
+        if window.is_key_down(Key::Down) {
             pos += 0.1;
         }
-        if device.window.is_key_down(Key::Left) {
+        if window.is_key_down(Key::Left) {
             alpha += 0.1;
         }
-        if device.window.is_key_down(Key::Right) {
+        if window.is_key_down(Key::Right) {
             alpha -= 0.1;
         }
 
-        if device.window.is_key_down(Key::Space) {
+        if window.is_key_down(Key::Space) {
             if kbhit == 0 {
                 kbhit += 1;
                 indicator += 1;
-                if indicator >= 3 {
+                if indicator >= arr.len() {
                     indicator = 0;
                 }
                 device.render_state = arr[indicator];
@@ -61,7 +178,99 @@ fn main() {
                 kbhit = 0;
             }
         }
-        device.draw_box(alpha);
-        device.window.update_with_buffer(&device.framebuf, WIDTH, HEIGHT).unwrap();
+        let axis = Vector4f::direction(-1., -0.5, 1.);
+        scene.nodes[root].rotation = Quaternion::from_axis_angle(axis, alpha);
+        scene.nodes[satellite].rotation = Quaternion::from_axis_angle(axis, alpha * 2.);
+        scene.update_world();
+        match tracer.as_mut() {
+            Some(tracer) => {
+                if window.is_key_down(Key::Left) || window.is_key_down(Key::Right) {
+                    tracer.reset();
+                }
+                tracer.render(&device, &scene);
+                device.present(&tracer.image());
+            }
+            None => { device.draw_scene(&scene); }
+        }
+        for (i, key) in post_keys.iter().enumerate() {
+            if window.is_key_pressed(*key, KeyRepeat::No) {
+                device.post.toggle(i);
+            }
+        }
+        device.post_process();
+        device.resolve();
+        if window.is_key_pressed(Key::H, KeyRepeat::No) {
+            let on = device.hdr.is_none();
+            device.set_hdr(on);
+        }
+        if window.is_key_pressed(Key::B, KeyRepeat::No) {
+            std::mem::swap(&mut sky, &mut device.skybox);
+        }
+        // F cycles no fog, linear, exp and exp2
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            let haze = Color::new(0.6, 0.6, 0.65);
+            device.fog = match device.fog.map(|f| f.mode) {
+                None => { Some(Fog::linear(haze, 2.0, 12.0)) }
+                Some(FogMode::Linear) => { Some(Fog::exp(haze, 0.15)) }
+                Some(FogMode::Exp) => { Some(Fog::exp2(haze, 0.12)) }
+                Some(FogMode::Exp2) => { None }
+            };
+        }
+        if window.is_key_pressed(Key::G, KeyRepeat::No) {
+            device.srgb = !device.srgb;
+        }
+        if window.is_key_pressed(Key::T, KeyRepeat::No) {
+            device.tone_map = device.tone_map.next();
+        }
+        if window.is_key_down(Key::Equal) {
+            device.exposure *= 1.05;
+        }
+        if window.is_key_down(Key::Minus) {
+            device.exposure /= 1.05;
+        }
+        let mouse_down = window.get_mouse_down(MouseButton::Left);
+        if mouse_down && !mouse_was_down {
+            if let Some((mx, my)) = window.get_mouse_pos(MouseMode::Discard) {
+                let hit = device.pick(mx as usize, my as usize).or_else(|| device.pick_raycast(&scene, mx as usize, my as usize));
+                picked = match hit {
+                    Some(hit) => { format!("{} triangle {} depth {:.2}", scene.nodes[hit.object as usize].name, hit.triangle, hit.depth) }
+                    None => { String::from("owo") }
+                };
+            }
+        }
+        mouse_was_down = mouse_down;
+        let stats = device.stats;
+        let status = format!("{} | objects {} drawn {} culled, triangles {} drawn {} culled", picked,
+                             stats.objects_drawn, stats.objects_culled, stats.triangles_drawn, stats.triangles_culled);
+        if status != title {
+            window.set_title(&status);
+            title = status;
+        }
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            tracer = match tracer {
+                Some(_) => { None }
+                None => { Some(PathTracer::new(WIDTH, HEIGHT)) }
+            };
+        }
+        if window.is_key_pressed(Key::M, KeyRepeat::No) {
+            show_shadow_map = !show_shadow_map;
+        }
+        if window.is_key_pressed(Key::N, KeyRepeat::No) {
+            match device.normal_map.take() {
+                Some(handle) => { device.textures.remove(handle); }
+                None => { device.init_normal_map(); }
+            }
+        }
+        if show_shadow_map {
+            device.draw_shadow_map(0, 0, 0, 200);
+        }
+        if window.is_key_pressed(Key::S, KeyRepeat::No) {
+            let mut ppm = format!("P6\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+            ppm.extend(device.read_pixels(PixelFormat::Rgb8));
+            if let Err(e) = std::fs::write("screenshot.ppm", ppm) {
+                println!("screenshot.ppm: {}", e);
+            }
+        }
+        window.update_with_buffer(device.framebuf.as_slice(), WIDTH, HEIGHT).unwrap();
     }
 }
\ No newline at end of file
@@ -0,0 +1,89 @@
+use crate::calc::Color;
+use crate::texture::TextureHandle;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendMode {
+    Opaque,
+    AlphaBlend, // src * alpha + dst * (1 - alpha), no depth write
+    Additive,   // dst + src * alpha, no depth write
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadingModel {
+    BlinnPhong, // base_color, specular, shininess
+    Pbr,        // Cook-Torrance GGX: base_color, metallic, roughness, ao
+}
+
+// Texture slots are handles into Device::textures; an empty slot (or a freed
+// texture) is unused, except the diffuse map which falls back to the
+// device texture. Reflections and refractions look up env_map as a sphere
+// map, or the device skybox when it is empty. metallic_roughness_map follows
+// glTF: roughness in green, metallic in blue, each scaling the constant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Material {
+    pub shading: ShadingModel,
+    pub base_color: Color,
+    pub alpha: f32,
+    pub diffuse_map: Option<TextureHandle>,
+    pub specular: Color,
+    pub specular_map: Option<TextureHandle>,
+    pub shininess: f32,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub metallic_roughness_map: Option<TextureHandle>,
+    pub ao: f32,
+    pub ao_map: Option<TextureHandle>, // red channel
+    pub normal_map: Option<TextureHandle>,
+    pub emissive: Color,
+    pub emissive_map: Option<TextureHandle>,
+    pub reflectivity: f32,
+    pub refractivity: f32,
+    pub ior: f32,
+    pub env_map: Option<TextureHandle>,
+    pub double_sided: bool,
+    pub blend: BlendMode,
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            shading: ShadingModel::BlinnPhong,
+            base_color: Color { r: 1.0, g: 1.0, b: 1.0 },
+            alpha: 1.0,
+            diffuse_map: None,
+            specular: Color { r: 0.0, g: 0.0, b: 0.0 },
+            specular_map: None,
+            shininess: 32.0,
+            metallic: 0.0,
+            roughness: 0.5,
+            metallic_roughness_map: None,
+            ao: 1.0,
+            ao_map: None,
+            normal_map: None,
+            emissive: Color { r: 0.0, g: 0.0, b: 0.0 },
+            emissive_map: None,
+            reflectivity: 0.0,
+            refractivity: 0.0,
+            ior: 1.5,
+            env_map: None,
+            double_sided: false,
+            blend: BlendMode::Opaque,
+        }
+    }
+}
+
+impl Material {
+    pub fn new() -> Material {
+        Material::default()
+    }
+
+    pub fn pbr(base_color: Color, metallic: f32, roughness: f32) -> Material {
+        Material {
+            shading: ShadingModel::Pbr,
+            base_color,
+            metallic,
+            roughness,
+            ..Material::default()
+        }
+    }
+}
@@ -1,8 +1,23 @@
 use std::f32::consts::PI;
 use std::num;
+use crate::quaternion_calc::Quaternion;
 use crate::vector_calc::Vector4f;
 
-#[derive(Clone, Copy)]
+// Treats det as zero when it is within rounding of the largest value it
+// could have for these rows (Hadamard's bound, the product of the row
+// lengths), so the test doesn't depend on the matrix's overall scale.
+fn is_singular(det: f32, rows: &[[f32; 4]], n: usize) -> bool {
+    if det == 0.0 || !det.is_finite() {
+        return true;
+    }
+    let mut bound = 1.0f32;
+    for row in rows.iter() {
+        bound *= row[..n].iter().map(|v| v * v).sum::<f32>().sqrt();
+    }
+    det.abs() <= f32::EPSILON * bound
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct Matrix4f {
     pub m: [[f32; 4]; 4],
 }
@@ -17,48 +32,219 @@ impl Matrix4f {
         }
     }
 
-    pub fn add(&mut self, x: Matrix4f, y: Matrix4f) {
-        for i in 0..4 {
-            for j in 0..4 {
-                self.m[i][j] = x.m[i][j] + y.m[i][j];
-            }
+    pub fn identity() -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.set_identity();
+        ret
+    }
+
+    pub fn translation(x: f32, y: f32, z: f32) -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.set_translate(x, y, z);
+        ret
+    }
+
+    pub fn scaling(x: f32, y: f32, z: f32) -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.set_scale(x, y, z);
+        ret
+    }
+
+    pub fn rotation(x: f32, y: f32, z: f32, theta: f32) -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.set_rotation(x, y, z, theta);
+        ret
+    }
+
+    pub fn lookat(eye: Vector4f, at: Vector4f, up: Vector4f) -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.set_lookat(eye, at, up);
+        ret
+    }
+
+    pub fn perspective(eye_fov: f32, aspect_ratio: f32, z_near: f32, z_far: f32) -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.set_perspective(eye_fov, aspect_ratio, z_near, z_far);
+        ret
+    }
+
+    pub fn orthographic(left: f32, right: f32, bottom: f32, top: f32, z_near: f32, z_far: f32) -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.set_orthographic(left, right, bottom, top, z_near, z_far);
+        ret
+    }
+
+    pub fn transposed(&self) -> Matrix4f {
+        let mut ret = Matrix4f::new();
+        ret.transpose(*self);
+        ret
+    }
+
+    pub fn inverted(&self) -> Option<Matrix4f> {
+        let mut ret = Matrix4f::new();
+        match ret.inverse(*self) {
+            true => { Some(ret) }
+            false => { None }
         }
     }
 
-    pub fn sub(&mut self, x: Matrix4f, y: Matrix4f) {
-        for i in 0..4 {
-            for j in 0..4 {
-                self.m[i][j] = x.m[i][j] - y.m[i][j];
-            }
+    pub fn inverted_affine(&self) -> Option<Matrix4f> {
+        let mut ret = Matrix4f::new();
+        match ret.inverse_affine(*self) {
+            true => { Some(ret) }
+            false => { None }
         }
     }
 
-    pub fn mul(&mut self, x: Matrix4f, y: Matrix4f) {
-        let mut z: Matrix4f = Matrix4f {
-            m: [[0., 0., 0., 0.],
-                [0., 0., 0., 0.],
-                [0., 0., 0., 0.],
-                [0., 0., 0., 0.]]
-        };
-        for i in 0..4 {
-            for j in 0..4 {
-                z.m[j][i] = (x.m[j][0] * y.m[0][i])
-                    + (x.m[j][1] * y.m[1][i])
-                    + (x.m[j][2] * y.m[2][i])
-                    + (x.m[j][3] * y.m[3][i]);
-            }
+    pub fn to_normal_matrix(self) -> Option<Matrix4f> {
+        let mut ret = Matrix4f::new();
+        match ret.normal_matrix(self) {
+            true => { Some(ret) }
+            false => { None }
         }
-        self.m = z.m;
+    }
+
+    pub fn add(&mut self, x: Matrix4f, y: Matrix4f) {
+        *self = x + y;
+    }
+
+    pub fn sub(&mut self, x: Matrix4f, y: Matrix4f) {
+        *self = x - y;
+    }
+
+    pub fn mul(&mut self, x: Matrix4f, y: Matrix4f) {
+        *self = x * y;
     }
 
     pub fn scale(&mut self, x: Matrix4f, f: f32) {
+        *self = x * f;
+    }
+
+    pub fn transpose(&mut self, x: Matrix4f) {
         for i in 0..4 {
             for j in 0..4 {
-                self.m[i][j] = x.m[i][j] * f;
+                self.m[i][j] = x.m[j][i];
             }
         }
     }
 
+    pub fn determinant(&self) -> f32 {
+        let a = &self.m;
+        let s0 = a[0][0] * a[1][1] - a[1][0] * a[0][1];
+        let s1 = a[0][0] * a[1][2] - a[1][0] * a[0][2];
+        let s2 = a[0][0] * a[1][3] - a[1][0] * a[0][3];
+        let s3 = a[0][1] * a[1][2] - a[1][1] * a[0][2];
+        let s4 = a[0][1] * a[1][3] - a[1][1] * a[0][3];
+        let s5 = a[0][2] * a[1][3] - a[1][2] * a[0][3];
+        let c5 = a[2][2] * a[3][3] - a[3][2] * a[2][3];
+        let c4 = a[2][1] * a[3][3] - a[3][1] * a[2][3];
+        let c3 = a[2][1] * a[3][2] - a[3][1] * a[2][2];
+        let c2 = a[2][0] * a[3][3] - a[3][0] * a[2][3];
+        let c1 = a[2][0] * a[3][2] - a[3][0] * a[2][2];
+        let c0 = a[2][0] * a[3][1] - a[3][0] * a[2][1];
+        s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0
+    }
+
+    // Returns false and leaves self untouched when x is singular.
+    pub fn inverse(&mut self, x: Matrix4f) -> bool {
+        let a = &x.m;
+        let s0 = a[0][0] * a[1][1] - a[1][0] * a[0][1];
+        let s1 = a[0][0] * a[1][2] - a[1][0] * a[0][2];
+        let s2 = a[0][0] * a[1][3] - a[1][0] * a[0][3];
+        let s3 = a[0][1] * a[1][2] - a[1][1] * a[0][2];
+        let s4 = a[0][1] * a[1][3] - a[1][1] * a[0][3];
+        let s5 = a[0][2] * a[1][3] - a[1][2] * a[0][3];
+        let c5 = a[2][2] * a[3][3] - a[3][2] * a[2][3];
+        let c4 = a[2][1] * a[3][3] - a[3][1] * a[2][3];
+        let c3 = a[2][1] * a[3][2] - a[3][1] * a[2][2];
+        let c2 = a[2][0] * a[3][3] - a[3][0] * a[2][3];
+        let c1 = a[2][0] * a[3][2] - a[3][0] * a[2][2];
+        let c0 = a[2][0] * a[3][1] - a[3][0] * a[2][1];
+        let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+        if is_singular(det, a, 4) {
+            return false;
+        }
+        let inv = 1.0 / det;
+        let mut b = Matrix4f::new();
+        b.m[0][0] = (a[1][1] * c5 - a[1][2] * c4 + a[1][3] * c3) * inv;
+        b.m[0][1] = (-a[0][1] * c5 + a[0][2] * c4 - a[0][3] * c3) * inv;
+        b.m[0][2] = (a[3][1] * s5 - a[3][2] * s4 + a[3][3] * s3) * inv;
+        b.m[0][3] = (-a[2][1] * s5 + a[2][2] * s4 - a[2][3] * s3) * inv;
+        b.m[1][0] = (-a[1][0] * c5 + a[1][2] * c2 - a[1][3] * c1) * inv;
+        b.m[1][1] = (a[0][0] * c5 - a[0][2] * c2 + a[0][3] * c1) * inv;
+        b.m[1][2] = (-a[3][0] * s5 + a[3][2] * s2 - a[3][3] * s1) * inv;
+        b.m[1][3] = (a[2][0] * s5 - a[2][2] * s2 + a[2][3] * s1) * inv;
+        b.m[2][0] = (a[1][0] * c4 - a[1][1] * c2 + a[1][3] * c0) * inv;
+        b.m[2][1] = (-a[0][0] * c4 + a[0][1] * c2 - a[0][3] * c0) * inv;
+        b.m[2][2] = (a[3][0] * s4 - a[3][1] * s2 + a[3][3] * s0) * inv;
+        b.m[2][3] = (-a[2][0] * s4 + a[2][1] * s2 - a[2][3] * s0) * inv;
+        b.m[3][0] = (-a[1][0] * c3 + a[1][1] * c1 - a[1][2] * c0) * inv;
+        b.m[3][1] = (a[0][0] * c3 - a[0][1] * c1 + a[0][2] * c0) * inv;
+        b.m[3][2] = (-a[3][0] * s3 + a[3][1] * s1 - a[3][2] * s0) * inv;
+        b.m[3][3] = (a[2][0] * s3 - a[2][1] * s1 + a[2][2] * s0) * inv;
+        self.m = b.m;
+        true
+    }
+
+    pub fn is_affine(&self) -> bool {
+        self.m[0][3] == 0.0 && self.m[1][3] == 0.0 && self.m[2][3] == 0.0 && self.m[3][3] == 1.0
+    }
+
+    // Fast path for rotation/scale/translation matrices (row vectors, translation
+    // in the last row). Anything with a projective column goes through inverse().
+    pub fn inverse_affine(&mut self, x: Matrix4f) -> bool {
+        if !x.is_affine() {
+            return self.inverse(x);
+        }
+        let mut r = Matrix4f::new();
+        if !r.inverse3(x) {
+            return false;
+        }
+        let t = [x.m[3][0], x.m[3][1], x.m[3][2]];
+        for j in 0..3 {
+            r.m[3][j] = -(t[0] * r.m[0][j] + t[1] * r.m[1][j] + t[2] * r.m[2][j]);
+        }
+        r.m[3][3] = 1.0;
+        self.m = r.m;
+        true
+    }
+
+    // Inverse-transpose of the upper 3x3, for transforming normals so they stay
+    // perpendicular to surfaces under non-uniform scale.
+    pub fn normal_matrix(&mut self, x: Matrix4f) -> bool {
+        let mut r = Matrix4f::new();
+        if !r.inverse3(x) {
+            return false;
+        }
+        self.transpose(r);
+        self.m[3][3] = 1.0;
+        true
+    }
+
+    // Inverts the upper 3x3 block of x into self, zeroing the rest.
+    fn inverse3(&mut self, x: Matrix4f) -> bool {
+        let a = &x.m;
+        let c00 = a[1][1] * a[2][2] - a[1][2] * a[2][1];
+        let c01 = a[1][2] * a[2][0] - a[1][0] * a[2][2];
+        let c02 = a[1][0] * a[2][1] - a[1][1] * a[2][0];
+        let det = a[0][0] * c00 + a[0][1] * c01 + a[0][2] * c02;
+        if is_singular(det, &a[..3], 3) {
+            return false;
+        }
+        let inv = 1.0 / det;
+        self.set_zero();
+        self.m[0][0] = c00 * inv;
+        self.m[1][0] = c01 * inv;
+        self.m[2][0] = c02 * inv;
+        self.m[0][1] = (a[0][2] * a[2][1] - a[0][1] * a[2][2]) * inv;
+        self.m[1][1] = (a[0][0] * a[2][2] - a[0][2] * a[2][0]) * inv;
+        self.m[2][1] = (a[0][1] * a[2][0] - a[0][0] * a[2][1]) * inv;
+        self.m[0][2] = (a[0][1] * a[1][2] - a[0][2] * a[1][1]) * inv;
+        self.m[1][2] = (a[0][2] * a[1][0] - a[0][0] * a[1][2]) * inv;
+        self.m[2][2] = (a[0][0] * a[1][1] - a[0][1] * a[1][0]) * inv;
+        true
+    }
+
     pub fn set_identity(&mut self) {
         self.m[0][0] = 1.; self.m[1][1] = 1.; self.m[2][2] = 1.; self.m[3][3] = 1.;
         self.m[0][1] = 0.; self.m[0][2] = 0.; self.m[0][3] = 0.;
@@ -89,31 +275,9 @@ impl Matrix4f {
         self.m[2][2] = z;
     }
 
-    pub fn set_rotation(&mut self, mut x: f32, mut y: f32, mut z: f32, dolta: f32) {
-        let qsin = (dolta * 0.5).sin();
-        let qcos = (dolta * 0.5).cos();
-        let mut vec = Vector4f{ x, y, z, w: 1.0 };
-        let w = qcos;
-        vec.normalize();
-        x = vec.x * qsin;
-        y = vec.y * qsin;
-        z = vec.z * qsin;
-        self.m[0][0] = 1. - 2. * y * y - 2. * z * z;
-        self.m[1][0] = 2. * x * y - 2. * w * z;
-        self.m[2][0] = 2. * x * z + 2. * w * y;
-        self.m[0][1] = 2. * x * y + 2. * w * z;
-        self.m[1][1] = 1. - 2. * x * x - 2. * z * z;
-        self.m[2][1] = 2. * y * z - 2. * w * x;
-        self.m[0][2] = 2. * x * z - 2. * w * y;
-        self.m[1][2] = 2. * y * z + 2. * w * x;
-        self.m[2][2] = 1. - 2. * x * x - 2. * y * y;
-        self.m[0][3] = 0.0;
-        self.m[1][3] = 0.0;
-        self.m[2][3] = 0.0;
-        self.m[3][0] = 0.0;
-        self.m[3][1] = 0.0;
-        self.m[3][2] = 0.0;
-        self.m[3][3] = 1.0;
+    pub fn set_rotation(&mut self, x: f32, y: f32, z: f32, dolta: f32) {
+        let q = Quaternion::from_axis_angle(Vector4f::direction(x, y, z), dolta);
+        self.m = q.to_matrix().m;
     }
 
     //Camera
@@ -161,4 +325,195 @@ impl Matrix4f {
         self.m[3][2] = -zNear * zFar / (zFar - zNear);
         self.m[2][3] = 1.0;
     }
-}
\ No newline at end of file
+
+    // Same depth range as set_perspective: z_near maps to 0 and z_far to 1.
+    pub fn set_orthographic(&mut self, left: f32, right: f32, bottom: f32, top: f32, z_near: f32, z_far: f32) {
+        self.set_identity();
+        self.m[0][0] = 2.0 / (right - left);
+        self.m[1][1] = 2.0 / (top - bottom);
+        self.m[2][2] = 1.0 / (z_far - z_near);
+        self.m[3][0] = -(right + left) / (right - left);
+        self.m[3][1] = -(top + bottom) / (top - bottom);
+        self.m[3][2] = -z_near / (z_far - z_near);
+    }
+}
+
+impl std::ops::Add for Matrix4f {
+    type Output = Matrix4f;
+    fn add(self, y: Matrix4f) -> Matrix4f {
+        let mut z = Matrix4f::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                z.m[i][j] = self.m[i][j] + y.m[i][j];
+            }
+        }
+        z
+    }
+}
+
+impl std::ops::Sub for Matrix4f {
+    type Output = Matrix4f;
+    fn sub(self, y: Matrix4f) -> Matrix4f {
+        let mut z = Matrix4f::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                z.m[i][j] = self.m[i][j] - y.m[i][j];
+            }
+        }
+        z
+    }
+}
+
+// x * y applies x first, then y, matching the row-vector convention.
+impl std::ops::Mul for Matrix4f {
+    type Output = Matrix4f;
+    fn mul(self, y: Matrix4f) -> Matrix4f {
+        let mut z = Matrix4f::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                z.m[j][i] = (self.m[j][0] * y.m[0][i])
+                    + (self.m[j][1] * y.m[1][i])
+                    + (self.m[j][2] * y.m[2][i])
+                    + (self.m[j][3] * y.m[3][i]);
+            }
+        }
+        z
+    }
+}
+
+impl std::ops::Mul<f32> for Matrix4f {
+    type Output = Matrix4f;
+    fn mul(self, f: f32) -> Matrix4f {
+        let mut z = Matrix4f::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                z.m[i][j] = self.m[i][j] * f;
+            }
+        }
+        z
+    }
+}
+
+impl std::ops::Neg for Matrix4f {
+    type Output = Matrix4f;
+    fn neg(self) -> Matrix4f {
+        self * -1.0
+    }
+}
+
+impl std::ops::Index<usize> for Matrix4f {
+    type Output = [f32; 4];
+    fn index(&self, row: usize) -> &[f32; 4] {
+        &self.m[row]
+    }
+}
+
+impl std::ops::IndexMut<usize> for Matrix4f {
+    fn index_mut(&mut self, row: usize) -> &mut [f32; 4] {
+        &mut self.m[row]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Matrix4f;
+    use crate::vector_calc::Vector4f;
+
+    fn from_rows(m: [[f32; 4]; 4]) -> Matrix4f {
+        Matrix4f { m }
+    }
+
+    fn assert_near(a: Matrix4f, b: Matrix4f, eps: f32) {
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((a.m[i][j] - b.m[i][j]).abs() <= eps, "[{}][{}]: {:?} vs {:?}", i, j, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_known_values() {
+        let m = from_rows([[1., 2., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 3.], [0., 0., 0., 1.]]);
+        let expected = from_rows([[1., -2., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., -3.], [0., 0., 0., 1.]]);
+        assert_near(m.inverted().unwrap(), expected, 1e-6);
+        // scale then translate, undone as translate back then scale back
+        let m = Matrix4f::scaling(2., 4., 8.) * Matrix4f::translation(1., 2., 3.);
+        let expected = from_rows([[0.5, 0., 0., 0.], [0., 0.25, 0., 0.], [0., 0., 0.125, 0.], [-0.5, -0.5, -0.375, 1.]]);
+        assert_near(m.inverted().unwrap(), expected, 1e-6);
+        let m = Matrix4f::lookat(Vector4f::point(3., 1., 2.), Vector4f::point(0., 0., 0.), Vector4f::direction(0., 0., 1.))
+            * Matrix4f::perspective(1.2, 4.0 / 3.0, 1.0, 500.0);
+        assert_near(m * m.inverted().unwrap(), Matrix4f::identity(), 1e-4);
+    }
+
+    #[test]
+    fn inverse3_known_values() {
+        let m = from_rows([[2., 0., 0., 9.], [0., 0., 1., 9.], [0., 4., 0., 9.], [9., 9., 9., 9.]]);
+        let mut inv = Matrix4f::new();
+        assert!(inv.inverse3(m));
+        let expected = from_rows([[0.5, 0., 0., 0.], [0., 0., 0.25, 0.], [0., 1., 0., 0.], [0., 0., 0., 0.]]);
+        assert_near(inv, expected, 1e-6);
+    }
+
+    #[test]
+    fn determinant_known_values() {
+        let m = from_rows([[2., 0., 0., 0.], [0., 3., 0., 0.], [0., 0., 4., 0.], [0., 0., 0., 5.]]);
+        assert_eq!(m.determinant(), 120.0);
+        let m = from_rows([[0., 3., 0., 0.], [2., 0., 0., 0.], [0., 0., 4., 0.], [0., 0., 0., 5.]]);
+        assert_eq!(m.determinant(), -120.0);
+        let m = from_rows([[1., 2., 3., 4.], [0., 2., 5., 6.], [0., 0., 3., 7.], [0., 0., 0., 4.]]);
+        assert_eq!(m.determinant(), 24.0);
+        assert!((Matrix4f::rotation(1., 2., 3., 0.7).determinant() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn transpose_swaps_rows_and_columns() {
+        let m = from_rows([[1., 2., 3., 4.], [5., 6., 7., 8.], [9., 10., 11., 12.], [13., 14., 15., 16.]]);
+        let expected = from_rows([[1., 5., 9., 13.], [2., 6., 10., 14.], [3., 7., 11., 15.], [4., 8., 12., 16.]]);
+        assert_eq!(m.transposed(), expected);
+        assert_eq!(m.transposed().transposed(), m);
+    }
+
+    #[test]
+    fn affine_inverse_matches_general() {
+        let m = Matrix4f::scaling(0.5, 2., 3.) * Matrix4f::rotation(-1., 0.5, 1., 1.1) * Matrix4f::translation(4., -2., 7.);
+        assert!(m.is_affine());
+        assert_near(m.inverted_affine().unwrap(), m.inverted().unwrap(), 1e-5);
+        assert_near(m * m.inverted_affine().unwrap(), Matrix4f::identity(), 1e-5);
+    }
+
+    #[test]
+    fn small_scale_is_invertible() {
+        // det is 1e-12 but the matrix is perfectly well conditioned
+        let m = Matrix4f::scaling(1e-4, 1e-4, 1e-4);
+        assert_near(m.inverted().unwrap(), Matrix4f::scaling(1e4, 1e4, 1e4), 1e-2);
+        let m = m * Matrix4f::translation(100., 0., 0.);
+        assert_near(m * m.inverted_affine().unwrap(), Matrix4f::identity(), 1e-4);
+        assert!(m.inverted().is_some());
+        assert!(m.to_normal_matrix().is_some());
+    }
+
+    #[test]
+    fn normal_matrix_keeps_normals_perpendicular() {
+        let m = Matrix4f::scaling(2., 1., 1.);
+        let n = m.to_normal_matrix().unwrap();
+        assert_near(n, Matrix4f::scaling(0.5, 1., 1.), 1e-6);
+        // the plane x = y: tangent (1, -1, 0) has normal (1, 1, 0)
+        let t = Vector4f::direction(1., -1., 0.) * m;
+        let normal = Vector4f::direction(1., 1., 0.) * n;
+        assert!(t.dot(normal).abs() < 1e-6);
+    }
+
+    #[test]
+    fn singular_matrices_have_no_inverse() {
+        let m = from_rows([[1., 2., 3., 4.], [2., 4., 6., 8.], [0., 1., 0., 0.], [0., 0., 1., 1.]]);
+        assert_eq!(m.determinant(), 0.0);
+        assert!(m.inverted().is_none());
+        let flat = Matrix4f::scaling(1., 0., 1.);
+        assert!(flat.inverted().is_none());
+        assert!(flat.inverted_affine().is_none());
+        assert!(flat.to_normal_matrix().is_none());
+        let mut out = Matrix4f::identity();
+        assert!(!out.inverse(flat));
+        assert_eq!(out, Matrix4f::identity());
+    }
+}
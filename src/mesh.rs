@@ -0,0 +1,173 @@
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::bvh::{Bvh, BvhSplit};
+use crate::calc::Color;
+use crate::material::{BlendMode, Material};
+use crate::vector_calc::{Vector3, Vector4f};
+use crate::vertex::Vertex;
+
+// Range of triangles drawn with their own material.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct SubMesh {
+    pub start: usize, // first triangle
+    pub count: usize,
+    pub material: Option<usize>,
+}
+
+// Indexed triangle list, every three indices form one triangle. Materials
+// index into Scene::materials; triangles outside every submesh use material.
+#[derive(Clone, Default, Debug)]
+pub struct Mesh {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<usize>,
+    pub material: Option<usize>,
+    pub submeshes: Vec<SubMesh>,
+    pub aabb: Aabb, // empty until compute_bounds(), which disables culling
+    pub sphere: BoundingSphere,
+    pub bvh: Option<Bvh>, // see build_bvh()
+}
+
+impl Mesh {
+    pub fn new() -> Mesh {
+        Mesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            material: None,
+            submeshes: Vec::new(),
+            aabb: Aabb::empty(),
+            sphere: BoundingSphere::default(),
+            bvh: None,
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    pub fn material_of(&self, triangle: usize) -> Option<usize> {
+        for sub in self.submeshes.iter() {
+            if triangle >= sub.start && triangle < sub.start + sub.count {
+                return sub.material;
+            }
+        }
+        self.material
+    }
+
+    // Number of (opaque, blended) triangles once every submesh has its
+    // material from `materials` (Scene::materials).
+    pub fn blend_counts(&self, materials: &[Material]) -> (usize, usize) {
+        let blended = |m: Option<usize>| m.and_then(|m| materials.get(m)).is_some_and(|m| m.blend != BlendMode::Opaque);
+        let mut counts = (0, 0);
+        let mut covered = 0;
+        for sub in self.submeshes.iter() {
+            match blended(sub.material) {
+                true => { counts.1 += sub.count; }
+                false => { counts.0 += sub.count; }
+            }
+            covered += sub.count;
+        }
+        let rest = self.triangle_count().saturating_sub(covered);
+        match blended(self.material) {
+            true => { counts.1 += rest; }
+            false => { counts.0 += rest; }
+        }
+        counts
+    }
+
+    pub fn triangle(&self, i: usize) -> (Vertex, Vertex, Vertex) {
+        (self.vertices[self.indices[i * 3]],
+                self.vertices[self.indices[i * 3 + 1]],
+                self.vertices[self.indices[i * 3 + 2]])
+    }
+    // Call again after editing vertex positions; also refits the BVH.
+    pub fn compute_bounds(&mut self) {
+        let points: Vec<Vector3> = self.vertices.iter().map(|v| v.pos.xyz()).collect();
+        self.aabb = Aabb::from_points(&points);
+        self.sphere = BoundingSphere::from_points(&points);
+        if let Some(mut bvh) = self.bvh.take() {
+            bvh.refit(self);
+            self.bvh = Some(bvh);
+        }
+    }
+
+    // Speeds up ray casts and per-triangle frustum culling. Rebuild after
+    // changing indices.
+    pub fn build_bvh(&mut self, split: BvhSplit) {
+        self.bvh = Some(Bvh::build(self, split));
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        for v in self.vertices.iter_mut() {
+            v.color = color;
+        }
+    }
+
+    // Smooth normals from area-weighted face normals. Vertices on UV seams are
+    // separate vertices, so they keep a visible crease.
+    pub fn compute_normals(&mut self) {
+        for v in self.vertices.iter_mut() {
+            v.normal = Vector4f::direction(0.0, 0.0, 0.0);
+        }
+        for t in 0..self.triangle_count() {
+            let (a, b, c) = (self.indices[t * 3], self.indices[t * 3 + 1], self.indices[t * 3 + 2]);
+            let e1 = self.vertices[b].pos - self.vertices[a].pos;
+            let e2 = self.vertices[c].pos - self.vertices[a].pos;
+            let n = e1.cross(e2);
+            for &i in [a, b, c].iter() {
+                self.vertices[i].normal = self.vertices[i].normal + n;
+            }
+        }
+        for v in self.vertices.iter_mut() {
+            v.normal.normalize();
+        }
+    }
+
+    // Per-vertex tangent frames from texture coordinates, in the spirit of
+    // MikkTSpace: face tangents are weighted by the corner angle, projected
+    // onto the vertex normal and the bitangent direction is kept in w.
+    // Needs normals, so run compute_normals() first if the mesh has none.
+    pub fn compute_tangents(&mut self) {
+        let n = self.vertices.len();
+        let mut tan = vec![Vector3::default(); n];
+        let mut bit = vec![Vector3::default(); n];
+        for t in 0..self.triangle_count() {
+            let idx = [self.indices[t * 3], self.indices[t * 3 + 1], self.indices[t * 3 + 2]];
+            let v = [self.vertices[idx[0]], self.vertices[idx[1]], self.vertices[idx[2]]];
+            let e1 = (v[1].pos - v[0].pos).xyz();
+            let e2 = (v[2].pos - v[0].pos).xyz();
+            let (du1, dv1) = (v[1].tc.u - v[0].tc.u, v[1].tc.v - v[0].tc.v);
+            let (du2, dv2) = (v[2].tc.u - v[0].tc.u, v[2].tc.v - v[0].tc.v);
+            let det = du1 * dv2 - du2 * dv1;
+            if det.abs() < 1e-12 {
+                continue;
+            }
+            let r = 1.0 / det;
+            let ft = (e1 * dv2 - e2 * dv1) * r;
+            let fb = (e2 * du1 - e1 * du2) * r;
+            for k in 0..3 {
+                let a = (v[(k + 1) % 3].pos - v[k].pos).xyz().normalized();
+                let b = (v[(k + 2) % 3].pos - v[k].pos).xyz().normalized();
+                let angle = a.dot(b).clamp(-1.0, 1.0).acos();
+                tan[idx[k]] = tan[idx[k]] + ft * angle;
+                bit[idx[k]] = bit[idx[k]] + fb * angle;
+            }
+        }
+        for i in 0..n {
+            let normal = self.vertices[i].normal.xyz().normalized();
+            let mut t = tan[i] - normal * normal.dot(tan[i]);
+            if t.length() < 1e-12 {
+                // no usable UVs here, any vector perpendicular to the normal will do
+                let axis = match normal.x.abs() < 0.9 {
+                    true => { Vector3::new(1.0, 0.0, 0.0) }
+                    false => { Vector3::new(0.0, 1.0, 0.0) }
+                };
+                t = axis - normal * normal.dot(axis);
+            }
+            t = t.normalized();
+            let w = match normal.cross(t).dot(bit[i]) < 0.0 {
+                true => { -1.0 }
+                false => { 1.0 }
+            };
+            self.vertices[i].tangent = Vector4f { x: t.x, y: t.y, z: t.z, w };
+        }
+    }
+}
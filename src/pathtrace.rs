@@ -0,0 +1,421 @@
+use std::f32::consts::PI;
+use crate::buffer::Buffer2D;
+use crate::calc::Color;
+use crate::device::Device;
+use crate::light::{Light, LightKind};
+use crate::material::{BlendMode, Material, ShadingModel};
+use crate::matrix_calc::Matrix4f;
+use crate::mesh::Mesh;
+use crate::pixel::srgb_decode;
+use crate::ray::{raycast_mesh, screen_ray_inverse, Ray, RayHit};
+use crate::scene::Scene;
+use crate::shading::{cook_torrance, decode_normal, light_incidence, perturb_normal, reflect, refract};
+use crate::vector_calc::Vector4f;
+
+// Pushes secondary rays off the surface they start on.
+const RAY_OFFSET: f32 = 1e-3;
+
+// Progressive Monte Carlo reference for the rasterizer: every render() adds
+// one jittered sample per pixel of the same scene, camera, lights and
+// materials, with shadows, interreflection and ambient occlusion traced
+// instead of approximated. Units follow the raster path (light intensities
+// are pi-scaled) and Device::ambient acts as a uniform sky when there is no
+// skybox, so an unoccluded diffuse surface reads the same in both.
+// Differences on purpose: Blinn-Phong specular is energy normalized,
+// reflectivity and refractivity pick a mirror or refracted path with that
+// probability instead of blending an environment lookup, blended materials
+// are stochastically transparent by alpha, and toon shading, ao and env_map
+// are ignored.
+pub struct PathTracer {
+    pub accum: Buffer2D<Color>, // sum over samples
+    pub samples: u32,
+    pub max_bounces: usize,
+    camera: Matrix4f, // view * projection the samples were taken with
+}
+
+// A drawable with its matrices resolved for one pass.
+struct Instance<'a> {
+    mesh: &'a Mesh,
+    world: Matrix4f,
+    inv: Matrix4f,
+    normal: Matrix4f,
+}
+
+// Everything the integrator needs at a hit, in world space.
+struct Surface {
+    p: Vector4f,
+    ng: Vector4f, // geometric normal, facing the incoming ray
+    n: Vector4f,  // shading normal, same side as ng
+    entering: bool,
+    mat: Material,
+    albedo: Color,
+    specular: Color,
+    metallic: f32,
+    roughness: f32,
+    emissive: Color,
+}
+
+// PCG32, seeded per pixel and sample so passes are reproducible.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(x: usize, y: usize, sample: u32) -> Rng {
+        let seed = x as u64 | (y as u64) << 21 | (sample as u64) << 42;
+        let mut rng = Rng { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ 0xD1B5_4A32_D192_ED03 };
+        rng.next();
+        rng
+    }
+
+    fn next(&mut self) -> f32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        let r = xorshifted.rotate_right(rot);
+        (r >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
+// Cosine-weighted direction around unit n (Duff et al.'s orthonormal basis).
+fn cosine_sample(n: Vector4f, u1: f32, u2: f32) -> Vector4f {
+    let sign = 1.0f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    let t = Vector4f::direction(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+    let bt = Vector4f::direction(b, sign + n.y * n.y * a, -n.y);
+    let r = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    t * (r * phi.cos()) + bt * (r * phi.sin()) + n * (1.0 - u1).max(0.0).sqrt()
+}
+
+impl PathTracer {
+    pub fn new(width: usize, height: usize) -> PathTracer {
+        PathTracer {
+            accum: Buffer2D::new(width, height, Color::default()),
+            samples: 0,
+            max_bounces: 4,
+            camera: Matrix4f::identity(),
+        }
+    }
+
+    // Drops the accumulated samples. Needed after the scene changes; camera
+    // moves are noticed by render() itself.
+    pub fn reset(&mut self) {
+        self.accum.fill(Color::default());
+        self.samples = 0;
+    }
+
+    // Adds one sample per pixel of scene seen through device's camera,
+    // using its textures, skybox, ambient, fog and sRGB setting.
+    pub fn render(&mut self, device: &Device, scene: &Scene) {
+        let (w, h) = (device.framebuf.width, device.framebuf.height);
+        let camera = device.transform.view * device.transform.projection;
+        if self.accum.width != w || self.accum.height != h {
+            self.accum.resize(w, h, Color::default());
+            self.reset();
+        }
+        if camera != self.camera {
+            self.camera = camera;
+            self.reset();
+        }
+        let inv = match camera.inverted() {
+            Some(inv) => { inv }
+            None => { return; }
+        };
+        let instances: Vec<Instance> = scene.drawables().iter().filter_map(|&(node, mesh)| {
+            let world = scene.nodes[node].world;
+            Some(Instance {
+                mesh: &scene.meshes[mesh],
+                world,
+                inv: world.inverted()?,
+                normal: world.to_normal_matrix()?,
+            })
+        }).collect();
+        let lights = scene.lights();
+        for y in 0..h {
+            for x in 0..w {
+                let mut rng = Rng::new(x, y, self.samples);
+                let (sx, sy) = (x as f32 + rng.next(), y as f32 + rng.next());
+                let c = match screen_ray_inverse(inv, w as f32, h as f32, sx, sy) {
+                    Some(ray) => { self.radiance(device, scene, &instances, &lights, ray, &mut rng) }
+                    None => { Color::default() }
+                };
+                self.accum[(x, y)] = self.accum[(x, y)] + c;
+            }
+        }
+        self.samples += 1;
+    }
+
+    // The running average, in linear color.
+    pub fn image(&self) -> Buffer2D<Color> {
+        let mut ret = self.accum.clone();
+        let scale = 1.0 / self.samples.max(1) as f32;
+        for c in ret.as_mut_slice().iter_mut() {
+            *c = *c * scale;
+        }
+        ret
+    }
+
+    fn intersect<'a>(&self, instances: &'a [Instance], ray: &Ray) -> Option<(&'a Instance<'a>, RayHit)> {
+        let mut best: Option<(&Instance, RayHit)> = None;
+        for inst in instances.iter() {
+            // the world ray's t carries over into object space
+            if let Some(hit) = raycast_mesh(&ray.transformed(inst.inv), inst.mesh) {
+                if best.as_ref().is_none_or(|b| hit.t < b.1.t) {
+                    best = Some((inst, hit));
+                }
+            }
+        }
+        best
+    }
+
+    fn occluded(&self, instances: &[Instance], ray: &Ray, max_t: f32) -> bool {
+        self.intersect(instances, ray).is_some_and(|(_, hit)| hit.t < max_t)
+    }
+
+    fn background(&self, device: &Device, d: Vector4f) -> Color {
+        device.sample_environment(d).unwrap_or(device.ambient)
+    }
+
+    fn surface(&self, device: &Device, scene: &Scene, inst: &Instance, hit: &RayHit, ray: &Ray) -> Surface {
+        let i = hit.triangle as usize;
+        let (a, b, c) = inst.mesh.triangle(i);
+        let bc = hit.barycentric;
+        let mat = inst.mesh.material_of(i).and_then(|m| scene.materials.get(m)).copied().unwrap_or_default();
+        let (u, v) = (a.tc.u * bc.x + b.tc.u * bc.y + c.tc.u * bc.z, a.tc.v * bc.x + b.tc.v * bc.y + c.tc.v * bc.z);
+        let mut ng = ((b.pos - a.pos).cross(c.pos - a.pos) * inst.normal).normalized();
+        let n = a.normal * bc.x + b.normal * bc.y + c.normal * bc.z;
+        let mut n = (Vector4f { w: 0.0, ..n } * inst.normal).normalized();
+        if n.length() == 0.0 {
+            n = ng;
+        }
+        if ng.dot(n) < 0.0 {
+            ng = -ng;
+        }
+        let entering = ng.dot(ray.dir) < 0.0;
+        if !entering {
+            ng = -ng;
+            n = -n;
+        }
+        let normal_texel = match device.sample_slot(mat.normal_map, u, v, 0.0) {
+            Some(texel) => { Some(texel) }
+            None => { device.sample_slot(device.normal_map, u, v, 0.0) }
+        };
+        if let Some(texel) = normal_texel {
+            let t = a.tangent * bc.x + b.tangent * bc.y + c.tangent * bc.z;
+            let mut tangent = Vector4f { w: 0.0, ..t } * inst.world;
+            tangent.w = t.w;
+            n = perturb_normal(n, tangent, decode_normal(texel));
+            if n.dot(ng) <= 0.0 {
+                n = ng;
+            }
+        }
+        let base = match device.textured() {
+            true => {
+                device.sample_color(mat.diffuse_map, u, v, 0.0)
+                    .or_else(|| device.sample_color(device.texture, u, v, 0.0))
+                    .unwrap_or_default()
+            }
+            false => {
+                let vc = a.color * bc.x + b.color * bc.y + c.color * bc.z;
+                match device.srgb {
+                    true => { srgb_decode(vc) }
+                    false => { vc }
+                }
+            }
+        };
+        let specular = match device.sample_slot(mat.specular_map, u, v, 0.0) {
+            Some(texel) => { mat.specular * Color::from_u32(texel) }
+            None => { mat.specular }
+        };
+        let (mut metallic, mut roughness) = (mat.metallic, mat.roughness);
+        if let Some(texel) = device.sample_slot(mat.metallic_roughness_map, u, v, 0.0) {
+            roughness *= ((texel >> 8) & 0xFF) as f32 / 255.0;
+            metallic *= (texel & 0xFF) as f32 / 255.0;
+        }
+        let emissive = match device.sample_color(mat.emissive_map, u, v, 0.0) {
+            Some(texel) => { mat.emissive * texel }
+            None => { mat.emissive }
+        };
+        Surface {
+            p: ray.at(hit.t),
+            ng,
+            n,
+            entering,
+            mat,
+            albedo: base * mat.base_color,
+            specular,
+            metallic,
+            roughness: roughness.clamp(0.0, 1.0),
+            emissive,
+        }
+    }
+
+    // Reflected light towards v per unit incoming radiance from l, already
+    // multiplied by n.l and pi-scaled like cook_torrance.
+    fn brdf_cos(&self, s: &Surface, v: Vector4f, l: Vector4f) -> Color {
+        let n_dot_l = s.n.dot(l);
+        if n_dot_l <= 0.0 {
+            return Color::default();
+        }
+        match s.mat.shading {
+            ShadingModel::Pbr => { cook_torrance(s.n, v, l, s.albedo, s.metallic, s.roughness) }
+            ShadingModel::BlinnPhong => {
+                let h = (l + v).normalized();
+                let norm = (s.mat.shininess + 8.0) / 8.0;
+                (s.albedo + s.specular * (norm * s.n.dot(h).max(0.0).powf(s.mat.shininess))) * n_dot_l
+            }
+        }
+    }
+
+    fn direct(&self, s: &Surface, v: Vector4f, lights: &[Light], instances: &[Instance]) -> Color {
+        let mut ret = Color::default();
+        let origin = s.p + s.ng * RAY_OFFSET;
+        for light in lights.iter() {
+            let (l, intensity) = light_incidence(light, s.p);
+            if intensity <= 0.0 || s.ng.dot(l) <= 0.0 {
+                continue;
+            }
+            let f = self.brdf_cos(s, v, l);
+            if f.r + f.g + f.b <= 0.0 {
+                continue;
+            }
+            let max_t = match light.kind {
+                LightKind::Directional => { f32::MAX }
+                _ => { (light.position - origin).length() }
+            };
+            if !self.occluded(instances, &Ray::new(origin, l), max_t) {
+                ret = ret + f * light.color * intensity;
+            }
+        }
+        ret
+    }
+
+    fn radiance(&self, device: &Device, scene: &Scene, instances: &[Instance], lights: &[Light], mut ray: Ray, rng: &mut Rng) -> Color {
+        let mut ret = Color::default();
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut depth: Option<f32> = None;
+        for bounce in 0..=self.max_bounces {
+            let (inst, hit) = match self.intersect(instances, &ray) {
+                Some(h) => { h }
+                None => {
+                    ret = ret + throughput * self.background(device, ray.dir);
+                    break;
+                }
+            };
+            let s = self.surface(device, scene, inst, &hit, &ray);
+            if depth.is_none() {
+                depth = Some((s.p * device.transform.view).z);
+            }
+            if s.mat.blend != BlendMode::Opaque && rng.next() >= s.mat.alpha {
+                ray = Ray::new(s.p - s.ng * RAY_OFFSET, ray.dir);
+                continue;
+            }
+            ret = ret + throughput * s.emissive;
+            let choice = rng.next();
+            let (refl, refr) = (s.mat.reflectivity, s.mat.refractivity * (1.0 - s.mat.reflectivity));
+            if choice < refl {
+                ray = Ray::new(s.p + s.ng * RAY_OFFSET, reflect(ray.dir, s.n));
+                continue;
+            }
+            if choice < refl + refr {
+                let eta = match s.entering {
+                    true => { 1.0 / s.mat.ior }
+                    false => { s.mat.ior }
+                };
+                ray = match refract(ray.dir, s.n, eta) {
+                    Some(t) => { Ray::new(s.p - s.ng * RAY_OFFSET, t.normalized()) }
+                    None => { Ray::new(s.p + s.ng * RAY_OFFSET, reflect(ray.dir, s.n)) }
+                };
+                continue;
+            }
+            let v = -ray.dir;
+            ret = ret + throughput * self.direct(&s, v, lights, instances);
+            if bounce == self.max_bounces {
+                break;
+            }
+            let l = cosine_sample(s.n, rng.next(), rng.next());
+            let n_dot_l = s.n.dot(l);
+            if n_dot_l <= 0.0 || s.ng.dot(l) <= 0.0 {
+                break;
+            }
+            // cosine pdf cancels pi and n.l out of brdf_cos
+            throughput = throughput * self.brdf_cos(&s, v, l) * (1.0 / n_dot_l);
+            if bounce >= 2 {
+                let keep = throughput.r.max(throughput.g).max(throughput.b).clamp(0.05, 0.95);
+                if rng.next() >= keep {
+                    break;
+                }
+                throughput = throughput * (1.0 / keep);
+            }
+            ray = Ray::new(s.p + s.ng * RAY_OFFSET, l);
+        }
+        match (device.fog, depth) {
+            (Some(fog), Some(d)) => { fog.apply(ret, d) }
+            _ => { ret }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathTracer;
+    use crate::calc::Color;
+    use crate::device::Device;
+    use crate::light::Light;
+    use crate::mesh::Mesh;
+    use crate::quaternion_calc::Quaternion;
+    use crate::scene::{Node, Scene};
+    use crate::vector_calc::Vector4f;
+
+    // An unoccluded Lambert plane under one directional light and the ambient
+    // sky has to come out the same from both renderers.
+    #[test]
+    fn lambert_plane_matches_raster() {
+        let (w, h) = (32, 24);
+        let mut device = Device::init(w, h);
+        device.render_state = 4; // color
+        device.ambient = Color::new(0.2, 0.2, 0.2);
+        device.set_hdr(true);
+        let mut scene = Scene::new();
+        let mut plane = Mesh::plane(4.0, 4.0, 1, 1);
+        plane.set_color(Color::new(0.8, 0.6, 0.4));
+        let mut node = Node::new("plane");
+        node.mesh = Some(scene.add_mesh(plane));
+        scene.add_node(node, None);
+        let mut sun = Node::new("sun");
+        sun.light = Some(Light::directional(Color::new(1.0, 1.0, 1.0), 0.8));
+        sun.rotation = Quaternion::look_rotation(Vector4f::direction(-0.3, 0.2, -1.0), Vector4f::direction(0.0, 1.0, 0.0));
+        scene.add_node(sun, None);
+        scene.update_world();
+
+        device.clear(1);
+        device.camera_at_zero(3.0, 0.0, 2.0);
+        device.draw_scene(&scene);
+        let raster = device.hdr.clone().unwrap();
+        let mut tracer = PathTracer::new(w, h);
+        for _ in 0..64 {
+            tracer.render(&device, &scene);
+        }
+        let traced = tracer.image();
+
+        // averaged over pixels well inside the plane, away from jittered edges
+        let covered = |x: usize, y: usize| (y - 1..=y + 1).all(|j| (x - 1..=x + 1).all(|i| device.zbuffer[(i, j)] > 0.0));
+        let (mut a, mut b, mut n) = (Color::default(), Color::default(), 0);
+        for y in 1..h - 1 {
+            for x in 1..w - 1 {
+                if covered(x, y) {
+                    a = a + raster[(x, y)];
+                    b = b + traced[(x, y)];
+                    n += 1;
+                }
+            }
+        }
+        assert!(n > 50);
+        for (r, t) in [(a.r, b.r), (a.g, b.g), (a.b, b.b)] {
+            assert!((r - t).abs() <= r * 0.03, "raster {} traced {}", r, t);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::matrix_calc::Matrix4f;
+use crate::mesh::Mesh;
+use crate::ray::screen_ray;
+use crate::vector_calc::{Vector2, Vector3, Vector4f};
+
+// What is under a pixel. object is the scene node index (Device::object_id
+// at draw time), triangle indexes into the mesh, depth is view-space depth
+// and barycentric weights the triangle's three vertices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PickResult {
+    pub object: u32,
+    pub triangle: u32,
+    pub depth: f32,
+    pub barycentric: Vector3,
+}
+
+// Perspective-correct barycentrics of screen point q in the triangle with
+// screen positions p (x, y) and clip w kept in p.w.
+pub fn screen_barycentric(p: &[Vector4f; 3], q: Vector2) -> Vector3 {
+    let (a, b, c) = (p[0].xy(), p[1].xy(), p[2].xy());
+    let area = (b - a).cross(c - a);
+    if area == 0.0 {
+        return Vector3::new(1.0, 0.0, 0.0);
+    }
+    let edge = |a: Vector2, b: Vector2| (b - a).cross(q - a) / area;
+    let b = [edge(b, c), edge(c, a), edge(a, b)];
+    // screen-space weights are linear in 1/w, undo that
+    let b = [b[0] / p[0].w, b[1] / p[1].w, b[2] / p[2].w];
+    let sum = b[0] + b[1] + b[2];
+    if sum == 0.0 {
+        return Vector3::new(1.0, 0.0, 0.0);
+    }
+    Vector3::new(b[0] / sum, b[1] / sum, b[2] / sum)
+}
+
+// Nearest triangle of mesh under screen point (x, y), found by casting a ray
+// through the inverse of `transform` (object to clip space). w and h are the
+// screen size. Returns (triangle, object-space distance, barycentrics).
+pub fn raycast_mesh(mesh: &Mesh, transform: Matrix4f, w: f32, h: f32, x: f32, y: f32) -> Option<(u32, f32, Vector3)> {
+    let ray = screen_ray(transform, w, h, x, y)?;
+    crate::ray::raycast_mesh(&ray, mesh).map(|hit| (hit.triangle, hit.t, hit.barycentric))
+}
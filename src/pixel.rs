@@ -0,0 +1,279 @@
+use crate::buffer::Buffer2D;
+use crate::calc::{Color, CMID};
+
+// Byte layouts for color targets other than the window. Multi-byte values are
+// little-endian, so a framebuffer u32 (0x00RRGGBB) laid out in memory is Bgra8
+// with an unused alpha byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    Rgb8,    // R, G, B bytes, as in binary PPM
+    Rgba8,   // R, G, B, A bytes
+    Bgra8,   // B, G, R, A bytes
+    Rgb565,  // u16, red in the top 5 bits
+    Gray8,   // Rec. 709 luminance
+    Rgba32F, // four f32
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Rgba {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl Rgba {
+    pub fn new(r: f32, g: f32, b: f32, a: f32) -> Rgba {
+        Rgba { r, g, b, a }
+    }
+
+    pub fn from_color(c: Color, a: f32) -> Rgba {
+        Rgba { r: c.r, g: c.g, b: c.b, a }
+    }
+
+    pub fn color(&self) -> Color {
+        Color::new(self.r, self.g, self.b)
+    }
+}
+
+// 0..1 to an unsigned normalized integer of `bits` bits, rounded.
+pub fn to_unorm(v: f32, bits: u32) -> u32 {
+    let max = (1 << bits) - 1;
+    CMID((v * max as f32 + 0.5) as i32, 0, max) as u32
+}
+
+pub fn from_unorm(v: u32, bits: u32) -> f32 {
+    v as f32 / ((1 << bits) - 1) as f32
+}
+
+pub fn luminance(c: Color) -> f32 {
+    0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b
+}
+
+// sRGB transfer functions (IEC 61966-2-1), per channel.
+pub fn srgb_to_linear(v: f32) -> f32 {
+    match v <= 0.04045 {
+        true => { v / 12.92 }
+        false => { ((v + 0.055) / 1.055).powf(2.4) }
+    }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    match v <= 0.0031308 {
+        true => { v * 12.92 }
+        false => { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+    }
+}
+
+pub fn srgb_decode(c: Color) -> Color {
+    Color { r: srgb_to_linear(c.r), g: srgb_to_linear(c.g), b: srgb_to_linear(c.b) }
+}
+
+pub fn srgb_encode(c: Color) -> Color {
+    Color { r: linear_to_srgb(c.r), g: linear_to_srgb(c.g), b: linear_to_srgb(c.b) }
+}
+
+// The framebuffer / minifb layout, 0x00RRGGBB.
+pub fn pack_rgb(c: Color) -> u32 {
+    (to_unorm(c.r, 8) << 16) | (to_unorm(c.g, 8) << 8) | to_unorm(c.b, 8)
+}
+
+pub fn unpack_rgb(p: u32) -> Color {
+    Color {
+        r: from_unorm((p >> 16) & 0xFF, 8),
+        g: from_unorm((p >> 8) & 0xFF, 8),
+        b: from_unorm(p & 0xFF, 8),
+    }
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb8 => { 3 }
+            PixelFormat::Rgba8 => { 4 }
+            PixelFormat::Bgra8 => { 4 }
+            PixelFormat::Rgb565 => { 2 }
+            PixelFormat::Gray8 => { 1 }
+            PixelFormat::Rgba32F => { 16 }
+        }
+    }
+
+    // Writes one pixel into out[..bytes_per_pixel()].
+    pub fn pack(&self, c: Rgba, out: &mut [u8]) {
+        match self {
+            PixelFormat::Rgb8 => {
+                out[0] = to_unorm(c.r, 8) as u8;
+                out[1] = to_unorm(c.g, 8) as u8;
+                out[2] = to_unorm(c.b, 8) as u8;
+            }
+            PixelFormat::Rgba8 => {
+                out[0] = to_unorm(c.r, 8) as u8;
+                out[1] = to_unorm(c.g, 8) as u8;
+                out[2] = to_unorm(c.b, 8) as u8;
+                out[3] = to_unorm(c.a, 8) as u8;
+            }
+            PixelFormat::Bgra8 => {
+                out[0] = to_unorm(c.b, 8) as u8;
+                out[1] = to_unorm(c.g, 8) as u8;
+                out[2] = to_unorm(c.r, 8) as u8;
+                out[3] = to_unorm(c.a, 8) as u8;
+            }
+            PixelFormat::Rgb565 => {
+                let p = (to_unorm(c.r, 5) << 11) | (to_unorm(c.g, 6) << 5) | to_unorm(c.b, 5);
+                out[..2].copy_from_slice(&(p as u16).to_le_bytes());
+            }
+            PixelFormat::Gray8 => {
+                out[0] = to_unorm(luminance(c.color()), 8) as u8;
+            }
+            PixelFormat::Rgba32F => {
+                for (i, v) in [c.r, c.g, c.b, c.a].iter().enumerate() {
+                    out[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    // Formats without alpha read back as opaque.
+    pub fn unpack(&self, src: &[u8]) -> Rgba {
+        match self {
+            PixelFormat::Rgb8 => {
+                Rgba::new(from_unorm(src[0] as u32, 8), from_unorm(src[1] as u32, 8), from_unorm(src[2] as u32, 8), 1.0)
+            }
+            PixelFormat::Rgba8 => {
+                Rgba::new(from_unorm(src[0] as u32, 8), from_unorm(src[1] as u32, 8),
+                          from_unorm(src[2] as u32, 8), from_unorm(src[3] as u32, 8))
+            }
+            PixelFormat::Bgra8 => {
+                Rgba::new(from_unorm(src[2] as u32, 8), from_unorm(src[1] as u32, 8),
+                          from_unorm(src[0] as u32, 8), from_unorm(src[3] as u32, 8))
+            }
+            PixelFormat::Rgb565 => {
+                let p = u16::from_le_bytes([src[0], src[1]]) as u32;
+                Rgba::new(from_unorm(p >> 11, 5), from_unorm((p >> 5) & 0x3F, 6), from_unorm(p & 0x1F, 5), 1.0)
+            }
+            PixelFormat::Gray8 => {
+                let l = from_unorm(src[0] as u32, 8);
+                Rgba::new(l, l, l, 1.0)
+            }
+            PixelFormat::Rgba32F => {
+                let f = |i: usize| f32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]]);
+                Rgba::new(f(0), f(4), f(8), f(12))
+            }
+        }
+    }
+}
+
+// Re-encodes a tightly packed pixel array from one format to another.
+pub fn convert(src: &[u8], from: PixelFormat, to: PixelFormat) -> Vec<u8> {
+    let (sb, db) = (from.bytes_per_pixel(), to.bytes_per_pixel());
+    let mut ret = vec![0; src.len() / sb * db];
+    for (s, d) in src.chunks_exact(sb).zip(ret.chunks_exact_mut(db)) {
+        to.pack(from.unpack(s), d);
+    }
+    ret
+}
+
+// A framebuffer as tightly packed rows in `format`, alpha 1.
+pub fn encode(buf: &Buffer2D<u32>, format: PixelFormat) -> Vec<u8> {
+    let bpp = format.bytes_per_pixel();
+    let mut ret = vec![0; buf.width * buf.height * bpp];
+    for (p, d) in buf.as_slice().iter().zip(ret.chunks_exact_mut(bpp)) {
+        format.pack(Rgba::from_color(unpack_rgb(*p), 1.0), d);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{convert, encode, pack_rgb, unpack_rgb, PixelFormat, Rgba};
+    use crate::buffer::Buffer2D;
+    use crate::texture::Texture;
+
+    fn round_trip(format: PixelFormat, c: Rgba) -> Rgba {
+        let mut bytes = vec![0; format.bytes_per_pixel()];
+        format.pack(c, &mut bytes);
+        format.unpack(&bytes)
+    }
+
+    fn assert_near(a: Rgba, b: Rgba, eps: [f32; 4]) {
+        let (a, b) = ([a.r, a.g, a.b, a.a], [b.r, b.g, b.b, b.a]);
+        for k in 0..4 {
+            assert!((a[k] - b[k]).abs() <= eps[k], "{:?} vs {:?}", a, b);
+        }
+    }
+
+    fn samples() -> Vec<Rgba> {
+        vec![
+            Rgba::new(0.0, 0.0, 0.0, 0.0),
+            Rgba::new(1.0, 1.0, 1.0, 1.0),
+            Rgba::new(1.0, 0.0, 0.0, 0.5),
+            Rgba::new(0.2, 0.7, 0.35, 0.9),
+            Rgba::new(0.91, 0.05, 0.5, 0.25),
+        ]
+    }
+
+    #[test]
+    fn rgb565_round_trip() {
+        // half a step of 5 and 6 bits, alpha is not stored
+        for c in samples() {
+            let back = round_trip(PixelFormat::Rgb565, c);
+            assert_near(back, Rgba { a: 1.0, ..c }, [0.5 / 31.0, 0.5 / 63.0, 0.5 / 31.0, 0.0]);
+        }
+        let mut bytes = [0; 2];
+        PixelFormat::Rgb565.pack(Rgba::new(1.0, 0.0, 0.0, 1.0), &mut bytes);
+        assert_eq!(u16::from_le_bytes(bytes), 0xf800);
+        PixelFormat::Rgb565.pack(Rgba::new(0.0, 1.0, 0.0, 1.0), &mut bytes);
+        assert_eq!(u16::from_le_bytes(bytes), 0x07e0);
+    }
+
+    #[test]
+    fn bgra8_round_trip() {
+        for c in samples() {
+            assert_near(round_trip(PixelFormat::Bgra8, c), c, [0.5 / 255.0 + 1e-6; 4]);
+        }
+        let mut bytes = [0; 4];
+        PixelFormat::Bgra8.pack(Rgba::new(1.0, 0.5, 0.0, 1.0), &mut bytes);
+        assert_eq!(bytes, [0, 128, 255, 255]);
+        // the framebuffer's own memory layout
+        assert_eq!(&bytes[..3], &pack_rgb(Rgba::new(1.0, 0.5, 0.0, 1.0).color()).to_le_bytes()[..3]);
+    }
+
+    #[test]
+    fn rgba32f_round_trip_is_exact() {
+        let hdr = Rgba::new(12.5, -0.25, 1e-7, 3.0);
+        for c in samples().into_iter().chain(std::iter::once(hdr)) {
+            assert_eq!(round_trip(PixelFormat::Rgba32F, c), c);
+        }
+    }
+
+    #[test]
+    fn packed_rgb_round_trip() {
+        for &p in [0x000000, 0xffffff, 0x3fbcef, 0x123456, 0x800001].iter() {
+            assert_eq!(pack_rgb(unpack_rgb(p)), p);
+        }
+    }
+
+    #[test]
+    fn convert_swaps_channels() {
+        let rgba = [255, 128, 0, 64, 1, 2, 3, 4];
+        let bgra = convert(&rgba, PixelFormat::Rgba8, PixelFormat::Bgra8);
+        assert_eq!(bgra, vec![0, 128, 255, 64, 3, 2, 1, 4]);
+        assert_eq!(convert(&bgra, PixelFormat::Bgra8, PixelFormat::Rgba8), rgba.to_vec());
+        assert_eq!(convert(&rgba, PixelFormat::Rgba8, PixelFormat::Rgb8), vec![255, 128, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn encoded_frame_reads_back_as_ppm() {
+        let mut buf = Buffer2D::new(3, 2, 0u32);
+        for (i, p) in [0xff0000, 0x00ff00, 0x0000ff, 0x3fbcef, 0x000000, 0xffffff].iter().enumerate() {
+            buf[(i % 3, i / 3)] = *p;
+        }
+        let mut ppm = b"P6\n3 2\n255\n".to_vec();
+        ppm.extend(encode(&buf, PixelFormat::Rgb8));
+        let tex = Texture::from_ppm(&ppm).unwrap();
+        assert_eq!((tex.width, tex.height), (3, 2));
+        assert_eq!(tex.mips[0], buf.as_slice().to_vec());
+        assert_eq!(encode(&buf, PixelFormat::Gray8)[4..], [0, 255]);
+    }
+}
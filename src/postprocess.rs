@@ -0,0 +1,256 @@
+use crate::buffer::Buffer2D;
+use crate::calc::Color;
+use crate::pixel::luminance;
+use crate::shading::smoothstep;
+
+// 3D color lookup table, size^3 entries with red varying fastest.
+#[derive(Clone, Debug)]
+pub struct ColorLut {
+    pub size: usize,
+    pub data: Vec<Color>,
+}
+
+impl ColorLut {
+    pub fn from_fn(size: usize, f: impl Fn(Color) -> Color) -> ColorLut {
+        let size = size.max(2);
+        let s = (size - 1) as f32;
+        let mut data = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    data.push(f(Color::new(r as f32 / s, g as f32 / s, b as f32 / s)));
+                }
+            }
+        }
+        ColorLut { size, data }
+    }
+
+    pub fn identity(size: usize) -> ColorLut {
+        ColorLut::from_fn(size, |c| c)
+    }
+
+    fn at(&self, r: usize, g: usize, b: usize) -> Color {
+        self.data[(b * self.size + g) * self.size + r]
+    }
+
+    // Trilinear lookup, input clamped to 0..1.
+    pub fn sample(&self, c: Color) -> Color {
+        let s = (self.size - 1) as f32;
+        let split = |v: f32| {
+            let f = v.clamp(0.0, 1.0) * s;
+            let i = (f as usize).min(self.size - 2);
+            (i, f - i as f32)
+        };
+        let (r, fr) = split(c.r);
+        let (g, fg) = split(c.g);
+        let (b, fb) = split(c.b);
+        let plane = |b: usize| {
+            let c0 = self.at(r, g, b).lerp(self.at(r + 1, g, b), fr);
+            let c1 = self.at(r, g + 1, b).lerp(self.at(r + 1, g + 1, b), fr);
+            c0.lerp(c1, fg)
+        };
+        plane(b).lerp(plane(b + 1), fb)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PostPass {
+    // Luma-based edge antialiasing (Lottes' FXAA, console variant).
+    Fxaa { span_max: f32, reduce_mul: f32, reduce_min: f32 },
+    // Bright parts above threshold, box blurred and added back.
+    Bloom { threshold: f32, intensity: f32, radius: usize },
+    // Darkens towards the corners; radius is where it starts, 1 = corner.
+    Vignette { strength: f32, radius: f32 },
+    ColorGrade { lut: ColorLut, strength: f32 },
+    Sharpen { amount: f32 },
+    Grayscale { amount: f32 },
+    // Blends towards a blurred copy as view depth moves away from focus,
+    // fully blurred range units off; the background counts as far away.
+    DepthOfField { focus: f32, range: f32, radius: usize },
+}
+
+#[derive(Clone, Debug)]
+pub struct PostEffect {
+    pub pass: PostPass,
+    pub enabled: bool,
+}
+
+// Passes run in order over linear color, before tone mapping; depth is the
+// device zbuffer (1/w, 0 for background).
+#[derive(Clone, Debug, Default)]
+pub struct PostChain {
+    pub effects: Vec<PostEffect>,
+}
+
+impl PostChain {
+    pub fn new() -> PostChain {
+        PostChain { effects: Vec::new() }
+    }
+
+    pub fn push(&mut self, pass: PostPass, enabled: bool) -> usize {
+        self.effects.push(PostEffect { pass, enabled });
+        self.effects.len() - 1
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.effects.iter().any(|e| e.enabled)
+    }
+
+    pub fn toggle(&mut self, i: usize) {
+        if let Some(e) = self.effects.get_mut(i) {
+            e.enabled = !e.enabled;
+        }
+    }
+
+    pub fn apply(&self, color: &mut Buffer2D<Color>, depth: &Buffer2D<f32>) {
+        for e in self.effects.iter().filter(|e| e.enabled) {
+            apply_pass(&e.pass, color, depth);
+        }
+    }
+}
+
+pub fn apply_pass(pass: &PostPass, color: &mut Buffer2D<Color>, depth: &Buffer2D<f32>) {
+    match pass {
+        PostPass::Fxaa { span_max, reduce_mul, reduce_min } => { fxaa(color, *span_max, *reduce_mul, *reduce_min) }
+        PostPass::Bloom { threshold, intensity, radius } => { bloom(color, *threshold, *intensity, *radius) }
+        PostPass::Vignette { strength, radius } => { vignette(color, *strength, *radius) }
+        PostPass::ColorGrade { lut, strength } => {
+            for c in color.as_mut_slice().iter_mut() {
+                *c = c.lerp(lut.sample(*c), *strength);
+            }
+        }
+        PostPass::Sharpen { amount } => { sharpen(color, *amount) }
+        PostPass::Grayscale { amount } => {
+            for c in color.as_mut_slice().iter_mut() {
+                let l = luminance(*c);
+                *c = c.lerp(Color::new(l, l, l), *amount);
+            }
+        }
+        PostPass::DepthOfField { focus, range, radius } => { depth_of_field(color, depth, *focus, *range, *radius) }
+    }
+}
+
+fn clamped(buf: &Buffer2D<Color>, x: i32, y: i32) -> Color {
+    let x = x.max(0).min(buf.width as i32 - 1);
+    let y = y.max(0).min(buf.height as i32 - 1);
+    buf[(x as usize, y as usize)]
+}
+
+// Bilinear sample with pixel (i, j) centred at (i, j).
+fn bilinear(buf: &Buffer2D<Color>, x: f32, y: f32) -> Color {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i32, y0 as i32);
+    let top = clamped(buf, x0, y0).lerp(clamped(buf, x0 + 1, y0), fx);
+    let bottom = clamped(buf, x0, y0 + 1).lerp(clamped(buf, x0 + 1, y0 + 1), fx);
+    top.lerp(bottom, fy)
+}
+
+fn fxaa(color: &mut Buffer2D<Color>, span_max: f32, reduce_mul: f32, reduce_min: f32) {
+    let src = color.clone();
+    for y in 0..src.height {
+        for x in 0..src.width {
+            let (xi, yi) = (x as i32, y as i32);
+            let nw = luminance(clamped(&src, xi - 1, yi - 1));
+            let ne = luminance(clamped(&src, xi + 1, yi - 1));
+            let sw = luminance(clamped(&src, xi - 1, yi + 1));
+            let se = luminance(clamped(&src, xi + 1, yi + 1));
+            let m = luminance(src[(x, y)]);
+            let luma_min = m.min(nw.min(ne).min(sw.min(se)));
+            let luma_max = m.max(nw.max(ne).max(sw.max(se)));
+            let mut dx = -((nw + ne) - (sw + se));
+            let mut dy = (nw + sw) - (ne + se);
+            let reduce = ((nw + ne + sw + se) * 0.25 * reduce_mul).max(reduce_min);
+            let scale = 1.0 / (dx.abs().min(dy.abs()) + reduce);
+            dx = (dx * scale).max(-span_max).min(span_max);
+            dy = (dy * scale).max(-span_max).min(span_max);
+            let (fx, fy) = (x as f32, y as f32);
+            let a = (bilinear(&src, fx - dx / 6.0, fy - dy / 6.0) + bilinear(&src, fx + dx / 6.0, fy + dy / 6.0)) * 0.5;
+            let b = a * 0.5 + (bilinear(&src, fx - dx * 0.5, fy - dy * 0.5) + bilinear(&src, fx + dx * 0.5, fy + dy * 0.5)) * 0.25;
+            let lb = luminance(b);
+            color[(x, y)] = match lb < luma_min || lb > luma_max {
+                true => { a }
+                false => { b }
+            };
+        }
+    }
+}
+
+// Separable box blur, edges clamped.
+fn box_blur(buf: &Buffer2D<Color>, radius: usize) -> Buffer2D<Color> {
+    let r = radius as i32;
+    let norm = 1.0 / (2 * r + 1) as f32;
+    let mut tmp = buf.clone();
+    for y in 0..buf.height {
+        for x in 0..buf.width {
+            let mut sum = Color::default();
+            for k in -r..=r {
+                sum = sum + clamped(buf, x as i32 + k, y as i32);
+            }
+            tmp[(x, y)] = sum * norm;
+        }
+    }
+    let mut ret = tmp.clone();
+    for y in 0..buf.height {
+        for x in 0..buf.width {
+            let mut sum = Color::default();
+            for k in -r..=r {
+                sum = sum + clamped(&tmp, x as i32, y as i32 + k);
+            }
+            ret[(x, y)] = sum * norm;
+        }
+    }
+    ret
+}
+
+fn bloom(color: &mut Buffer2D<Color>, threshold: f32, intensity: f32, radius: usize) {
+    let mut bright = color.clone();
+    for c in bright.as_mut_slice().iter_mut() {
+        *c = Color::new((c.r - threshold).max(0.0), (c.g - threshold).max(0.0), (c.b - threshold).max(0.0));
+    }
+    // two box passes approximate a gaussian
+    let blurred = box_blur(&box_blur(&bright, radius), radius);
+    for (c, b) in color.as_mut_slice().iter_mut().zip(blurred.as_slice().iter()) {
+        *c = *c + *b * intensity;
+    }
+}
+
+fn vignette(color: &mut Buffer2D<Color>, strength: f32, radius: f32) {
+    let (cx, cy) = (color.width as f32 * 0.5, color.height as f32 * 0.5);
+    let corner = (cx * cx + cy * cy).sqrt();
+    for y in 0..color.height {
+        for x in 0..color.width {
+            let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+            let d = (dx * dx + dy * dy).sqrt() / corner;
+            color[(x, y)] = color[(x, y)] * (1.0 - strength * smoothstep(radius, 1.0, d));
+        }
+    }
+}
+
+// Unsharp mask against the 4-neighbour average.
+fn sharpen(color: &mut Buffer2D<Color>, amount: f32) {
+    let src = color.clone();
+    for y in 0..src.height {
+        for x in 0..src.width {
+            let (xi, yi) = (x as i32, y as i32);
+            let avg = (clamped(&src, xi - 1, yi) + clamped(&src, xi + 1, yi)
+                + clamped(&src, xi, yi - 1) + clamped(&src, xi, yi + 1)) * 0.25;
+            let c = src[(x, y)];
+            color[(x, y)] = c + (c - avg) * amount;
+        }
+    }
+}
+
+fn depth_of_field(color: &mut Buffer2D<Color>, depth: &Buffer2D<f32>, focus: f32, range: f32, radius: usize) {
+    let blurred = box_blur(&box_blur(color, radius), radius);
+    for y in 0..color.height {
+        for x in 0..color.width {
+            let rhw = depth[(x, y)];
+            let t = match rhw > 0.0 {
+                true => { ((1.0 / rhw - focus).abs() / range.max(1e-6)).min(1.0) }
+                false => { 1.0 }
+            };
+            color[(x, y)] = color[(x, y)].lerp(blurred[(x, y)], t);
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use crate::calc::{Color, Texcoord};
+use crate::mesh::Mesh;
+use crate::vector_calc::{Vector3, Vector4f};
+use crate::vertex::Vertex;
+
+// Mesh generators. Shapes are centred on the origin with Z as their axis
+// (the camera's up direction), triangles wind counter-clockwise seen from
+// outside, and vertex colors default to the normal mapped into 0..1 so the
+// shading can be checked without any lights.
+
+fn normal_color(n: Vector3) -> Color {
+    Color {
+        r: n.x * 0.5 + 0.5,
+        g: n.y * 0.5 + 0.5,
+        b: n.z * 0.5 + 0.5,
+    }
+}
+
+fn push_vertex(mesh: &mut Mesh, pos: Vector3, normal: Vector3, u: f32, v: f32) -> usize {
+    let n = normal.normalized();
+    mesh.vertices.push(Vertex {
+        pos: pos.to_point(),
+        tc: Texcoord { u, v },
+        color: normal_color(n),
+        normal: n.to_direction(),
+        rhw: 1.0,
+        ..Vertex::new()
+    });
+    mesh.vertices.len() - 1
+}
+
+// Stitches a (rows + 1) x (cols + 1) vertex lattice starting at base into
+// quads, dropping triangles that collapse onto a pole.
+fn push_lattice(mesh: &mut Mesh, base: usize, rows: usize, cols: usize) {
+    for i in 0..rows {
+        for j in 0..cols {
+            let a = base + i * (cols + 1) + j;
+            let b = a + cols + 1;
+            for tri in [[a, b, b + 1], [a, b + 1, a + 1]].iter() {
+                let p0 = mesh.vertices[tri[0]].pos;
+                let p1 = mesh.vertices[tri[1]].pos;
+                let p2 = mesh.vertices[tri[2]].pos;
+                let eps = 1e-5;
+                if (p1 - p0).length() > eps && (p2 - p1).length() > eps && (p0 - p2).length() > eps {
+                    mesh.indices.extend_from_slice(tri);
+                }
+            }
+        }
+    }
+}
+
+impl Mesh {
+    // The demo box: same corners, colors and per-face texture coordinates as
+    // Device::draw_box, with four vertices per face.
+    pub fn cube() -> Mesh {
+        let corners = [
+            (Vector4f::point(-1.0, -1.0, 1.0), Color { r: 1.0, g: 0.2, b: 0.2 }),
+            (Vector4f::point(1.0, -1.0, 1.0), Color { r: 0.2, g: 1.0, b: 0.2 }),
+            (Vector4f::point(1.0, 1.0, 1.0), Color { r: 0.2, g: 0.2, b: 1.0 }),
+            (Vector4f::point(-1.0, 1.0, 1.0), Color { r: 1.0, g: 0.2, b: 1.0 }),
+            (Vector4f::point(-1.0, -1.0, -1.0), Color { r: 1.0, g: 1.0, b: 0.2 }),
+            (Vector4f::point(1.0, -1.0, -1.0), Color { r: 0.2, g: 1.0, b: 1.0 }),
+            (Vector4f::point(1.0, 1.0, -1.0), Color { r: 1.0, g: 0.3, b: 0.3 }),
+            (Vector4f::point(-1.0, 1.0, -1.0), Color { r: 0.2, g: 1.0, b: 0.3 }),
+        ];
+        let faces = [[0, 1, 2, 3], [7, 6, 5, 4], [0, 4, 5, 1], [1, 5, 6, 2], [2, 6, 7, 3], [3, 7, 4, 0]];
+        let tcs = [(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)];
+        let mut mesh = Mesh::new();
+        for face in faces.iter() {
+            let base = mesh.vertices.len();
+            let p0 = corners[face[0]].0;
+            let normal = (corners[face[1]].0 - p0).cross(corners[face[2]].0 - p0).normalized();
+            for k in 0..4 {
+                let (pos, color) = corners[face[k]];
+                mesh.vertices.push(Vertex {
+                    pos,
+                    tc: Texcoord { u: tcs[k].0, v: tcs[k].1 },
+                    color,
+                    normal,
+                    rhw: 1.0,
+                    ..Vertex::new()
+                });
+            }
+            mesh.indices.extend_from_slice(&[base, base + 1, base + 2, base + 2, base + 3, base]);
+        }
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Latitude/longitude sphere, rings >= 2 and segments >= 3.
+    pub fn uv_sphere(radius: f32, rings: usize, segments: usize) -> Mesh {
+        let rings = rings.max(2);
+        let segments = segments.max(3);
+        let mut mesh = Mesh::new();
+        for i in 0..=rings {
+            let theta = PI * i as f32 / rings as f32;
+            for j in 0..=segments {
+                let phi = 2.0 * PI * j as f32 / segments as f32;
+                let n = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                push_vertex(&mut mesh, n * radius, n, j as f32 / segments as f32, i as f32 / rings as f32);
+            }
+        }
+        push_lattice(&mut mesh, 0, rings, segments);
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Subdivided icosahedron with spherical texture coordinates. Triangles
+    // crossing the u seam get copies of their low-u vertices with u + 1, and
+    // pole vertices are copied per triangle with u taken from the other two
+    // corners, so no triangle interpolates across the whole texture. u goes
+    // past 1 on the seam, so textures for it want TextureAddress::Repeat.
+    pub fn ico_sphere(radius: f32, subdivisions: usize) -> Mesh {
+        let t = (1.0 + 5.0f32.sqrt()) * 0.5;
+        let mut points: Vec<Vector3> = [
+            Vector3::new(-1.0, t, 0.0), Vector3::new(1.0, t, 0.0),
+            Vector3::new(-1.0, -t, 0.0), Vector3::new(1.0, -t, 0.0),
+            Vector3::new(0.0, -1.0, t), Vector3::new(0.0, 1.0, t),
+            Vector3::new(0.0, -1.0, -t), Vector3::new(0.0, 1.0, -t),
+            Vector3::new(t, 0.0, -1.0), Vector3::new(t, 0.0, 1.0),
+            Vector3::new(-t, 0.0, -1.0), Vector3::new(-t, 0.0, 1.0),
+        ].iter().map(|p| p.normalized()).collect();
+        let mut faces: Vec<[usize; 3]> = vec![
+            [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+            [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+            [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+            [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+        ];
+        for _ in 0..subdivisions {
+            let mut cache: HashMap<(usize, usize), usize> = HashMap::new();
+            let mut midpoint = |a: usize, b: usize, points: &mut Vec<Vector3>| -> usize {
+                let key = (a.min(b), a.max(b));
+                if let Some(&i) = cache.get(&key) {
+                    return i;
+                }
+                points.push(((points[a] + points[b]) * 0.5).normalized());
+                cache.insert(key, points.len() - 1);
+                points.len() - 1
+            };
+            let mut next = Vec::with_capacity(faces.len() * 4);
+            for f in faces.iter() {
+                let ab = midpoint(f[0], f[1], &mut points);
+                let bc = midpoint(f[1], f[2], &mut points);
+                let ca = midpoint(f[2], f[0], &mut points);
+                next.push([f[0], ab, ca]);
+                next.push([f[1], bc, ab]);
+                next.push([f[2], ca, bc]);
+                next.push([ab, bc, ca]);
+            }
+            faces = next;
+        }
+        let mut mesh = Mesh::new();
+        for p in points.iter() {
+            let u = 0.5 + p.y.atan2(p.x) / (2.0 * PI);
+            let v = p.z.clamp(-1.0, 1.0).acos() / PI;
+            push_vertex(&mut mesh, *p * radius, *p, u, v);
+        }
+        let is_pole = |i: usize| points[i].x.abs() < 1e-6 && points[i].y.abs() < 1e-6;
+        let mut wrapped: HashMap<usize, usize> = HashMap::new();
+        for f in faces.iter() {
+            let mut tri = *f;
+            let poles = [is_pole(f[0]), is_pole(f[1]), is_pole(f[2])];
+            let us: Vec<f32> = tri.iter().filter(|&&i| !is_pole(i)).map(|&i| mesh.vertices[i].tc.u).collect();
+            let min = us.iter().fold(f32::MAX, |a, &b| a.min(b));
+            let max = us.iter().fold(-f32::MAX, |a, &b| a.max(b));
+            if max - min > 0.5 {
+                for k in 0..3 {
+                    let i = tri[k];
+                    if poles[k] || mesh.vertices[i].tc.u >= 0.5 {
+                        continue;
+                    }
+                    tri[k] = match wrapped.get(&i) {
+                        Some(&copy) => { copy }
+                        None => {
+                            let tc = mesh.vertices[i].tc;
+                            let copy = push_vertex(&mut mesh, points[i] * radius, points[i], tc.u + 1.0, tc.v);
+                            wrapped.insert(i, copy);
+                            copy
+                        }
+                    };
+                }
+            }
+            for k in 0..3 {
+                if !poles[k] {
+                    continue;
+                }
+                let i = tri[k];
+                let u = (mesh.vertices[tri[(k + 1) % 3]].tc.u + mesh.vertices[tri[(k + 2) % 3]].tc.u) * 0.5;
+                let v = mesh.vertices[i].tc.v;
+                tri[k] = push_vertex(&mut mesh, points[i] * radius, points[i], u, v);
+            }
+            mesh.indices.extend_from_slice(&tri);
+        }
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    pub fn cylinder(radius: f32, height: f32, segments: usize) -> Mesh {
+        Mesh::frustum_shape(radius, radius, height, segments)
+    }
+
+    pub fn cone(radius: f32, height: f32, segments: usize) -> Mesh {
+        Mesh::frustum_shape(radius, 0.0, height, segments)
+    }
+
+    // Capped side surface between a bottom circle at -height / 2 and a top
+    // circle at +height / 2; a zero top radius makes a cone.
+    fn frustum_shape(bottom: f32, top: f32, height: f32, segments: usize) -> Mesh {
+        let segments = segments.max(3);
+        let h = height * 0.5;
+        let mut mesh = Mesh::new();
+        // side normal tilt; atan2 keeps it finite for a zero height
+        let tilt = (bottom - top).atan2(height);
+        for i in 0..2 {
+            let (r, z) = match i {
+                0 => { (top, h) }
+                _ => { (bottom, -h) }
+            };
+            for j in 0..=segments {
+                let phi = 2.0 * PI * j as f32 / segments as f32;
+                let n = Vector3::new(phi.cos() * tilt.cos(), phi.sin() * tilt.cos(), tilt.sin());
+                let p = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+                push_vertex(&mut mesh, p, n, j as f32 / segments as f32, i as f32);
+            }
+        }
+        push_lattice(&mut mesh, 0, 1, segments);
+        for &(r, z, nz) in [(top, h, 1.0), (bottom, -h, -1.0)].iter() {
+            if r <= 0.0 {
+                continue;
+            }
+            let n = Vector3::new(0.0, 0.0, nz);
+            let center = push_vertex(&mut mesh, Vector3::new(0.0, 0.0, z), n, 0.5, 0.5);
+            for j in 0..=segments {
+                let phi = 2.0 * PI * j as f32 / segments as f32;
+                let p = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+                push_vertex(&mut mesh, p, n, 0.5 + 0.5 * phi.cos(), 0.5 + 0.5 * phi.sin());
+            }
+            for j in 0..segments {
+                let (a, b) = (center + 1 + j, center + 2 + j);
+                match nz > 0.0 {
+                    true => { mesh.indices.extend_from_slice(&[center, a, b]) }
+                    false => { mesh.indices.extend_from_slice(&[center, b, a]) }
+                }
+            }
+        }
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Ring of radius major around the Z axis swept by a circle of radius minor.
+    pub fn torus(major: f32, minor: f32, rings: usize, sides: usize) -> Mesh {
+        let rings = rings.max(3);
+        let sides = sides.max(3);
+        let mut mesh = Mesh::new();
+        for i in 0..=sides {
+            let theta = 2.0 * PI * i as f32 / sides as f32;
+            for j in 0..=rings {
+                let phi = 2.0 * PI * j as f32 / rings as f32;
+                let n = Vector3::new(theta.cos() * phi.cos(), theta.cos() * phi.sin(), theta.sin());
+                let center = Vector3::new(major * phi.cos(), major * phi.sin(), 0.0);
+                push_vertex(&mut mesh, center + n * minor, n, j as f32 / rings as f32, i as f32 / sides as f32);
+            }
+        }
+        // theta grows towards +Z, so the lattice winds the other way round
+        for i in 0..sides {
+            for j in 0..rings {
+                let a = i * (rings + 1) + j;
+                let b = a + rings + 1;
+                mesh.indices.extend_from_slice(&[a, a + 1, b + 1, a, b + 1, b]);
+            }
+        }
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Plane in XY facing +Z, split into x_segments by y_segments quads.
+    pub fn plane(width: f32, height: f32, x_segments: usize, y_segments: usize) -> Mesh {
+        let xs = x_segments.max(1);
+        let ys = y_segments.max(1);
+        let n = Vector3::new(0.0, 0.0, 1.0);
+        let mut mesh = Mesh::new();
+        for i in 0..=ys {
+            let v = i as f32 / ys as f32;
+            for j in 0..=xs {
+                let u = j as f32 / xs as f32;
+                let p = Vector3::new((u - 0.5) * width, (0.5 - v) * height, 0.0);
+                push_vertex(&mut mesh, p, n, u, v);
+            }
+        }
+        // rows run towards -Y, so flip the lattice winding to keep facing +Z
+        for i in 0..ys {
+            for j in 0..xs {
+                let a = i * (xs + 1) + j;
+                let b = a + xs + 1;
+                mesh.indices.extend_from_slice(&[a, b, b + 1, a, b + 1, a + 1]);
+            }
+        }
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Square plane with a two-tone checker in the vertex colors, handy as a
+    // floor when debugging lighting. Cells do not share vertices.
+    pub fn grid(size: f32, divisions: usize, c1: Color, c2: Color) -> Mesh {
+        let d = divisions.max(1);
+        let cell = size / d as f32;
+        let n = Vector3::new(0.0, 0.0, 1.0);
+        let mut mesh = Mesh::new();
+        for i in 0..d {
+            for j in 0..d {
+                let x0 = -size * 0.5 + j as f32 * cell;
+                let y0 = -size * 0.5 + i as f32 * cell;
+                let base = mesh.vertices.len();
+                let corners = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+                for &(cx, cy) in corners.iter() {
+                    let p = Vector3::new(x0 + cx * cell, y0 + cy * cell, 0.0);
+                    let u = (j as f32 + cx) / d as f32;
+                    let v = (i as f32 + cy) / d as f32;
+                    push_vertex(&mut mesh, p, n, u, v);
+                }
+                let color = match (i + j) & 1 > 0 {
+                    true => { c1 }
+                    false => { c2 }
+                };
+                for v in mesh.vertices[base..].iter_mut() {
+                    v.color = color;
+                }
+                mesh.indices.extend_from_slice(&[base, base + 1, base + 2, base + 2, base + 3, base]);
+            }
+        }
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+
+    // Cylinder of the given height with hemispherical caps, total length
+    // height + 2 * radius. rings is the number of bands per hemisphere.
+    pub fn capsule(radius: f32, height: f32, rings: usize, segments: usize) -> Mesh {
+        let rings = rings.max(1);
+        let segments = segments.max(3);
+        let h = height * 0.5;
+        let total = height + PI * radius;
+        let mut mesh = Mesh::new();
+        let mut rows = 0;
+        for half in 0..2 {
+            for i in 0..=rings {
+                let theta = (half * rings + i) as f32 / (2 * rings) as f32 * PI;
+                let z_offset = match half {
+                    0 => { h }
+                    _ => { -h }
+                };
+                let arc = theta * radius + match half {
+                    0 => { 0.0 }
+                    _ => { height }
+                };
+                for j in 0..=segments {
+                    let phi = 2.0 * PI * j as f32 / segments as f32;
+                    let n = Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                    let p = n * radius + Vector3::new(0.0, 0.0, z_offset);
+                    push_vertex(&mut mesh, p, n, j as f32 / segments as f32, arc / total);
+                }
+                rows += 1;
+            }
+        }
+        push_lattice(&mut mesh, 0, rows - 1, segments);
+        mesh.compute_bounds();
+        mesh.compute_tangents();
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mesh::Mesh;
+
+    // Every triangle winds counter-clockwise seen from the side its vertex
+    // normals point to, and normals are unit length.
+    fn assert_outward(mesh: &Mesh) {
+        for v in mesh.vertices.iter() {
+            assert!((v.normal.length() - 1.0).abs() < 1e-5, "{:?}", v.normal);
+            assert_eq!(v.normal.w, 0.0);
+        }
+        for i in 0..mesh.indices.len() / 3 {
+            let (a, b, c) = mesh.triangle(i);
+            let face = (b.pos - a.pos).cross(c.pos - a.pos);
+            assert!(face.length() > 0.0, "degenerate triangle {}", i);
+            assert!(face.dot(a.normal + b.normal + c.normal) > 0.0, "triangle {} winds inwards", i);
+        }
+    }
+
+    fn assert_uv_in(mesh: &Mesh, u_max: f32) {
+        for v in mesh.vertices.iter() {
+            assert!(v.tc.u >= 0.0 && v.tc.u <= u_max && v.tc.v >= 0.0 && v.tc.v <= 1.0, "{:?}", v.tc);
+        }
+    }
+
+    #[test]
+    fn cube_and_plane_counts() {
+        let cube = Mesh::cube();
+        assert_eq!((cube.vertices.len(), cube.indices.len()), (24, 36));
+        assert_outward(&cube);
+        assert_uv_in(&cube, 1.0);
+        let plane = Mesh::plane(2.0, 1.0, 3, 2);
+        assert_eq!((plane.vertices.len(), plane.indices.len()), (12, 36));
+        assert_outward(&plane);
+        assert_uv_in(&plane, 1.0);
+        assert!(plane.vertices.iter().all(|v| v.normal.z == 1.0 && v.pos.z == 0.0));
+    }
+
+    #[test]
+    fn uv_sphere_counts_normals_and_uvs() {
+        let (radius, rings, segments) = (2.0, 4, 8);
+        let mesh = Mesh::uv_sphere(radius, rings, segments);
+        assert_eq!(mesh.vertices.len(), (rings + 1) * (segments + 1));
+        // the triangle of each quad touching a pole collapses and is dropped
+        assert_eq!(mesh.indices.len() / 3, rings * segments * 2 - 2 * segments);
+        for v in mesh.vertices.iter() {
+            assert!((v.pos.length() - radius).abs() < 1e-5);
+            assert!((v.pos * (1.0 / radius) - v.normal).length() < 1e-5);
+        }
+        assert_outward(&mesh);
+        assert_uv_in(&mesh, 1.0);
+    }
+
+    #[test]
+    fn ico_sphere_counts_normals_and_uvs() {
+        for subdivisions in 0..3 {
+            let mesh = Mesh::ico_sphere(1.5, subdivisions);
+            assert_eq!(mesh.indices.len() / 3, 20 << (2 * subdivisions));
+            for v in mesh.vertices.iter() {
+                assert!((v.pos.length() - 1.5).abs() < 1e-5);
+                assert!((v.pos * (1.0 / 1.5) - v.normal).length() < 1e-5);
+            }
+            assert_outward(&mesh);
+            // seam copies run past 1, and no triangle spans the whole texture
+            // (one interpolating across the seam would span close to 1)
+            assert_uv_in(&mesh, 1.5);
+            for i in 0..mesh.indices.len() / 3 {
+                let (a, b, c) = mesh.triangle(i);
+                let us = [a.tc.u, b.tc.u, c.tc.u];
+                let spread = us.iter().cloned().fold(f32::MIN, f32::max) - us.iter().cloned().fold(f32::MAX, f32::min);
+                assert!(spread < 0.75, "triangle {} spans u {:?}", i, us);
+            }
+        }
+    }
+
+    #[test]
+    fn other_shapes_face_outwards() {
+        for mesh in [Mesh::cylinder(1.0, 2.0, 12), Mesh::cone(1.0, 2.0, 12), Mesh::torus(2.0, 0.5, 12, 8), Mesh::capsule(0.5, 1.0, 4, 12)].iter() {
+            assert!(!mesh.indices.is_empty());
+            assert_outward(mesh);
+            assert_uv_in(mesh, 1.0);
+        }
+        let cylinder = Mesh::cylinder(1.0, 2.0, 12);
+        // side lattice plus two caps with a center vertex each
+        assert_eq!(cylinder.vertices.len(), 2 * 13 + 2 * 14);
+        assert_eq!(cylinder.indices.len() / 3, 2 * 12 + 2 * 12);
+    }
+}
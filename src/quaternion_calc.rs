@@ -0,0 +1,342 @@
+use crate::matrix_calc::Matrix4f;
+use crate::vector_calc::{Vector3, Vector4f};
+
+// Rotation quaternion, (x, y, z) is the vector part and w the scalar part.
+// a * b is the Hamilton product: rotating by (a * b) applies b first, then a,
+// so (a * b).to_matrix() == b.to_matrix() * a.to_matrix().
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quaternion {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl Default for Quaternion {
+    fn default() -> Quaternion {
+        Quaternion::identity()
+    }
+}
+
+impl Quaternion {
+    pub fn new(x: f32, y: f32, z: f32, w: f32) -> Quaternion {
+        Quaternion { x, y, z, w }
+    }
+
+    pub fn identity() -> Quaternion {
+        Quaternion { x: 0.0, y: 0.0, z: 0.0, w: 1.0 }
+    }
+
+    pub fn from_axis_angle(axis: Vector4f, theta: f32) -> Quaternion {
+        let qsin = (theta * 0.5).sin();
+        let qcos = (theta * 0.5).cos();
+        let n = axis.normalized();
+        Quaternion {
+            x: n.x * qsin,
+            y: n.y * qsin,
+            z: n.z * qsin,
+            w: qcos,
+        }
+    }
+
+    // Angle around X is applied first, then Y, then Z.
+    pub fn from_euler(x: f32, y: f32, z: f32) -> Quaternion {
+        let (sr, cr) = (x * 0.5).sin_cos();
+        let (sp, cp) = (y * 0.5).sin_cos();
+        let (sy, cy) = (z * 0.5).sin_cos();
+        Quaternion {
+            x: sr * cp * cy - cr * sp * sy,
+            y: cr * sp * cy + sr * cp * sy,
+            z: cr * cp * sy - sr * sp * cy,
+            w: cr * cp * cy + sr * sp * sy,
+        }
+    }
+
+    // Inverse of from_euler; y is clamped to +-PI/2 at the poles.
+    pub fn to_euler(self) -> Vector3 {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+        let sinp = 2.0 * (w * y - z * x);
+        Vector3 {
+            x: (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y)),
+            y: sinp.clamp(-1.0, 1.0).asin(),
+            z: (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z)),
+        }
+    }
+
+    pub fn to_axis_angle(self) -> (Vector4f, f32) {
+        let q = self.normalized();
+        let theta = 2.0 * q.w.clamp(-1.0, 1.0).acos();
+        let s = (1.0 - q.w * q.w).max(0.0).sqrt();
+        if s < 1e-6 {
+            return (Vector4f::direction(1.0, 0.0, 0.0), theta);
+        }
+        (Vector4f::direction(q.x / s, q.y / s, q.z / s), theta)
+    }
+
+    // Same layout as Matrix4f::set_rotation: rows are the rotated basis vectors.
+    pub fn to_matrix(self) -> Matrix4f {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+        let mut m = Matrix4f::identity();
+        m.m[0][0] = 1. - 2. * y * y - 2. * z * z;
+        m.m[1][0] = 2. * x * y - 2. * w * z;
+        m.m[2][0] = 2. * x * z + 2. * w * y;
+        m.m[0][1] = 2. * x * y + 2. * w * z;
+        m.m[1][1] = 1. - 2. * x * x - 2. * z * z;
+        m.m[2][1] = 2. * y * z - 2. * w * x;
+        m.m[0][2] = 2. * x * z - 2. * w * y;
+        m.m[1][2] = 2. * y * z + 2. * w * x;
+        m.m[2][2] = 1. - 2. * x * x - 2. * y * y;
+        m
+    }
+
+    // Reads the rotation from the upper 3x3 of m, which must be orthonormal.
+    pub fn from_matrix(m: Matrix4f) -> Quaternion {
+        // r(i, j) is the column-vector form of the row-vector matrix.
+        let r = |i: usize, j: usize| m.m[j][i];
+        let trace = r(0, 0) + r(1, 1) + r(2, 2);
+        let q = if trace > 0.0 {
+            let s = 0.5 / (trace + 1.0).sqrt();
+            Quaternion {
+                x: (r(2, 1) - r(1, 2)) * s,
+                y: (r(0, 2) - r(2, 0)) * s,
+                z: (r(1, 0) - r(0, 1)) * s,
+                w: 0.25 / s,
+            }
+        } else if r(0, 0) > r(1, 1) && r(0, 0) > r(2, 2) {
+            let s = 2.0 * (1.0 + r(0, 0) - r(1, 1) - r(2, 2)).sqrt();
+            Quaternion {
+                x: 0.25 * s,
+                y: (r(0, 1) + r(1, 0)) / s,
+                z: (r(0, 2) + r(2, 0)) / s,
+                w: (r(2, 1) - r(1, 2)) / s,
+            }
+        } else if r(1, 1) > r(2, 2) {
+            let s = 2.0 * (1.0 + r(1, 1) - r(0, 0) - r(2, 2)).sqrt();
+            Quaternion {
+                x: (r(0, 1) + r(1, 0)) / s,
+                y: 0.25 * s,
+                z: (r(1, 2) + r(2, 1)) / s,
+                w: (r(0, 2) - r(2, 0)) / s,
+            }
+        } else {
+            let s = 2.0 * (1.0 + r(2, 2) - r(0, 0) - r(1, 1)).sqrt();
+            Quaternion {
+                x: (r(0, 2) + r(2, 0)) / s,
+                y: (r(1, 2) + r(2, 1)) / s,
+                z: 0.25 * s,
+                w: (r(1, 0) - r(0, 1)) / s,
+            }
+        };
+        q.normalized()
+    }
+
+    // Rotation taking local +Z to forward and local +Y towards up, the same
+    // basis set_lookat builds for the camera.
+    pub fn look_rotation(forward: Vector4f, up: Vector4f) -> Quaternion {
+        let zaxis = forward.normalized();
+        let mut xaxis = up.cross(zaxis);
+        if xaxis.length() < 1e-6 {
+            // forward is parallel to up, pick any perpendicular axis
+            xaxis = match zaxis.x.abs() < 0.9 {
+                true => { Vector4f::direction(1.0, 0.0, 0.0).cross(zaxis) }
+                false => { Vector4f::direction(0.0, 1.0, 0.0).cross(zaxis) }
+            };
+        }
+        xaxis.normalize();
+        let yaxis = zaxis.cross(xaxis);
+        let mut m = Matrix4f::identity();
+        m.m[0] = [xaxis.x, xaxis.y, xaxis.z, 0.0];
+        m.m[1] = [yaxis.x, yaxis.y, yaxis.z, 0.0];
+        m.m[2] = [zaxis.x, zaxis.y, zaxis.z, 0.0];
+        Quaternion::from_matrix(m)
+    }
+
+    pub fn dot(&self, q: Quaternion) -> f32 {
+        self.x * q.x + self.y * q.y + self.z * q.z + self.w * q.w
+    }
+
+    pub fn length(&self) -> f32 {
+        self.dot(*self).sqrt()
+    }
+
+    pub fn normalize(&mut self) {
+        let len = self.length();
+        if len != 0.0 {
+            let inv = 1.0 / len;
+            self.x *= inv;
+            self.y *= inv;
+            self.z *= inv;
+            self.w *= inv;
+        }
+    }
+
+    pub fn normalized(&self) -> Quaternion {
+        let mut ret = *self;
+        ret.normalize();
+        ret
+    }
+
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion { x: -self.x, y: -self.y, z: -self.z, w: self.w }
+    }
+
+    pub fn inverse(&self) -> Quaternion {
+        let sq = self.dot(*self);
+        if sq == 0.0 {
+            return *self;
+        }
+        let inv = 1.0 / sq;
+        let c = self.conjugate();
+        Quaternion { x: c.x * inv, y: c.y * inv, z: c.z * inv, w: c.w * inv }
+    }
+
+    // Rotates the xyz part of v and keeps its w, so points stay points.
+    pub fn rotate(&self, v: Vector4f) -> Vector4f {
+        let u = Vector4f::direction(self.x, self.y, self.z);
+        let t = u.cross(v) * 2.0;
+        let r = t * self.w + u.cross(t);
+        Vector4f { x: v.x + r.x, y: v.y + r.y, z: v.z + r.z, w: v.w }
+    }
+
+    pub fn nlerp(&self, q: Quaternion, t: f32) -> Quaternion {
+        let sign = match self.dot(q) < 0.0 {
+            true => { -1.0 }
+            false => { 1.0 }
+        };
+        let s = 1.0 - t;
+        let ret = Quaternion {
+            x: self.x * s + q.x * sign * t,
+            y: self.y * s + q.y * sign * t,
+            z: self.z * s + q.z * sign * t,
+            w: self.w * s + q.w * sign * t,
+        };
+        ret.normalized()
+    }
+
+    // Constant angular velocity along the shortest arc.
+    pub fn slerp(&self, q: Quaternion, t: f32) -> Quaternion {
+        let mut cos = self.dot(q);
+        let mut to = q;
+        if cos < 0.0 {
+            cos = -cos;
+            to = -q;
+        }
+        if cos > 0.9995 {
+            return self.nlerp(to, t);
+        }
+        let theta = cos.acos();
+        let sin = theta.sin();
+        let a = ((1.0 - t) * theta).sin() / sin;
+        let b = (t * theta).sin() / sin;
+        Quaternion {
+            x: self.x * a + to.x * b,
+            y: self.y * a + to.y * b,
+            z: self.z * a + to.z * b,
+            w: self.w * a + to.w * b,
+        }
+    }
+}
+
+impl std::ops::Mul for Quaternion {
+    type Output = Quaternion;
+    fn mul(self, q: Quaternion) -> Quaternion {
+        Quaternion {
+            x: self.w * q.x + self.x * q.w + self.y * q.z - self.z * q.y,
+            y: self.w * q.y - self.x * q.z + self.y * q.w + self.z * q.x,
+            z: self.w * q.z + self.x * q.y - self.y * q.x + self.z * q.w,
+            w: self.w * q.w - self.x * q.x - self.y * q.y - self.z * q.z,
+        }
+    }
+}
+
+impl std::ops::Neg for Quaternion {
+    type Output = Quaternion;
+    fn neg(self) -> Quaternion {
+        Quaternion { x: -self.x, y: -self.y, z: -self.z, w: -self.w }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quaternion;
+    use crate::vector_calc::Vector4f;
+
+    // q and -q are the same rotation.
+    fn assert_same_rotation(a: Quaternion, b: Quaternion, eps: f32) {
+        assert!(a.dot(b).abs() >= 1.0 - eps, "{:?} vs {:?}", a, b);
+    }
+
+    fn assert_near(a: Vector4f, b: Vector4f, eps: f32) {
+        assert!((a - b).length() <= eps && (a.w - b.w).abs() <= eps, "{:?} vs {:?}", a, b);
+    }
+
+    fn samples() -> Vec<Quaternion> {
+        vec![
+            Quaternion::identity(),
+            Quaternion::from_axis_angle(Vector4f::direction(1., 0., 0.), 0.5),
+            Quaternion::from_axis_angle(Vector4f::direction(0., 1., 0.), -2.0),
+            Quaternion::from_axis_angle(Vector4f::direction(0., 0., 1.), 3.1),
+            Quaternion::from_axis_angle(Vector4f::direction(1., -2., 0.5), 1.3),
+            // near 180 degrees, where the trace is negative
+            Quaternion::from_axis_angle(Vector4f::direction(-0.3, 1., 2.), 3.0),
+        ]
+    }
+
+    #[test]
+    fn matrix_round_trip() {
+        for q in samples() {
+            assert_same_rotation(Quaternion::from_matrix(q.to_matrix()), q, 1e-6);
+            let v = Vector4f::point(0.3, -1.2, 2.0);
+            assert_near(v * q.to_matrix(), q.rotate(v), 1e-5);
+        }
+    }
+
+    #[test]
+    fn axis_angle_round_trip() {
+        let axis = Vector4f::direction(1., -2., 0.5);
+        let q = Quaternion::from_axis_angle(axis, 1.3);
+        let (back, theta) = q.to_axis_angle();
+        assert!((theta - 1.3).abs() < 1e-5);
+        assert_near(back, axis.normalized(), 1e-5);
+        // a quarter turn about Z takes X to Y
+        let q = Quaternion::from_axis_angle(Vector4f::direction(0., 0., 1.), std::f32::consts::FRAC_PI_2);
+        assert_near(q.rotate(Vector4f::direction(1., 0., 0.)), Vector4f::direction(0., 1., 0.), 1e-6);
+        assert_eq!(Quaternion::identity().to_axis_angle().1, 0.0);
+    }
+
+    #[test]
+    fn euler_round_trip() {
+        let axis = |x: f32, y: f32, z: f32, theta: f32| Quaternion::from_axis_angle(Vector4f::direction(x, y, z), theta);
+        for &(x, y, z) in [(0.3, -0.4, 1.2), (-2.5, 1.0, 0.1), (0.0, 0.0, -3.0)].iter() {
+            let q = Quaternion::from_euler(x, y, z);
+            // X first, then Y, then Z
+            assert_same_rotation(q, axis(0., 0., 1., z) * axis(0., 1., 0., y) * axis(1., 0., 0., x), 1e-6);
+            let e = q.to_euler();
+            assert!((e.x - x).abs() < 1e-5 && (e.y - y).abs() < 1e-5 && (e.z - z).abs() < 1e-5, "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn slerp_endpoints_and_midpoint() {
+        let a = Quaternion::from_axis_angle(Vector4f::direction(0., 0., 1.), 0.2);
+        let b = Quaternion::from_axis_angle(Vector4f::direction(0., 0., 1.), 1.8);
+        assert_same_rotation(a.slerp(b, 0.0), a, 1e-6);
+        assert_same_rotation(a.slerp(b, 1.0), b, 1e-6);
+        let mid = Quaternion::from_axis_angle(Vector4f::direction(0., 0., 1.), 1.0);
+        assert_same_rotation(a.slerp(b, 0.5), mid, 1e-6);
+        // -b is the same rotation, slerp still takes the short way
+        assert_same_rotation(a.slerp(-b, 0.5), mid, 1e-6);
+        assert!((a.slerp(b, 0.3).length() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn look_rotation_known_basis() {
+        let q = Quaternion::look_rotation(Vector4f::direction(2., 0., 0.), Vector4f::direction(0., 0., 1.));
+        assert_near(q.rotate(Vector4f::direction(0., 0., 1.)), Vector4f::direction(1., 0., 0.), 1e-6);
+        assert_near(q.rotate(Vector4f::direction(0., 1., 0.)), Vector4f::direction(0., 0., 1.), 1e-6);
+        assert_near(q.rotate(Vector4f::direction(1., 0., 0.)), Vector4f::direction(0., 1., 0.), 1e-6);
+        // up parallel to forward still gives a rotation taking +Z to forward
+        let q = Quaternion::look_rotation(Vector4f::direction(0., 0., -1.), Vector4f::direction(0., 0., 1.));
+        assert_near(q.rotate(Vector4f::direction(0., 0., 1.)), Vector4f::direction(0., 0., -1.), 1e-6);
+    }
+}
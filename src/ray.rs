@@ -0,0 +1,255 @@
+use crate::bounds::Aabb;
+use crate::matrix_calc::Matrix4f;
+use crate::mesh::Mesh;
+use crate::vector_calc::{Vector3, Vector4f};
+
+// origin is a point (w = 1), dir a direction (w = 0), not necessarily unit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Vector4f,
+    pub dir: Vector4f,
+}
+
+// Nearest triangle along a ray. barycentric weights the triangle's three
+// vertices, t is in units of the ray's dir.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    pub triangle: u32,
+    pub t: f32,
+    pub barycentric: Vector3,
+}
+
+impl Ray {
+    pub fn new(origin: Vector4f, dir: Vector4f) -> Ray {
+        Ray { origin: Vector4f { w: 1.0, ..origin }, dir: Vector4f { w: 0.0, ..dir } }
+    }
+
+    pub fn at(&self, t: f32) -> Vector4f {
+        self.origin + self.dir * t
+    }
+
+    // dir is transformed but not renormalized, so t means the same point
+    // before and after. Used to take world rays into object space.
+    pub fn transformed(&self, m: Matrix4f) -> Ray {
+        Ray { origin: self.origin * m, dir: self.dir * m }
+    }
+}
+
+// Screen point (x, y) of a w x h target, at depth z (0 near, 1 far), back
+// through inv, the inverse of whatever Transform::apply used: the inverse of
+// Transform::transform lands in object space, that of view * projection in
+// world space.
+pub fn unproject_inverse(inv: Matrix4f, w: f32, h: f32, x: f32, y: f32, z: f32) -> Option<Vector4f> {
+    let ndc = Vector4f::point(x / w * 2.0 - 1.0, 1.0 - y / h * 2.0, z);
+    let p = ndc * inv;
+    if p.w == 0.0 {
+        return None;
+    }
+    Some(p.perspective_divide())
+}
+
+// Ray from the near plane through screen point (x, y), in the space m maps
+// from (see unproject_inverse). dir is unit length so t is a distance.
+pub fn screen_ray(m: Matrix4f, w: f32, h: f32, x: f32, y: f32) -> Option<Ray> {
+    screen_ray_inverse(m.inverted()?, w, h, x, y)
+}
+
+pub fn screen_ray_inverse(inv: Matrix4f, w: f32, h: f32, x: f32, y: f32) -> Option<Ray> {
+    let near = unproject_inverse(inv, w, h, x, y, 0.0)?;
+    let far = unproject_inverse(inv, w, h, x, y, 1.0)?;
+    Some(Ray::new(near, (far - near).normalized()))
+}
+
+// Möller–Trumbore. Returns (t, u, v) with the hit at a + u (b - a) + v (c - a),
+// both faces count, hits behind the origin do not.
+pub fn ray_triangle(ray: &Ray, a: Vector4f, b: Vector4f, c: Vector4f) -> Option<(f32, f32, f32)> {
+    let e1 = b - a;
+    let e2 = c - a;
+    let pv = ray.dir.cross(e2);
+    let det = e1.dot(pv);
+    // det scales with |dir| |e1| |e2|, so parallel is judged relative to that
+    if det.abs() <= f32::EPSILON * ray.dir.length() * e1.length() * e2.length() {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let tv = ray.origin - a;
+    let u = tv.dot(pv) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let qv = tv.cross(e1);
+    let v = ray.dir.dot(qv) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = e2.dot(qv) * inv_det;
+    if t < 0.0 {
+        return None;
+    }
+    Some((t, u, v))
+}
+
+// Slab test. Returns the (entry, exit) t range, entry clamped to 0 when the
+// origin is inside the box.
+pub fn ray_aabb(ray: &Ray, b: &Aabb) -> Option<(f32, f32)> {
+    if b.is_empty() {
+        return None;
+    }
+    let mut tmin: f32 = 0.0;
+    let mut tmax = f32::MAX;
+    for i in 0..3 {
+        let (o, d) = (ray.origin[i], ray.dir[i]);
+        if d == 0.0 {
+            if o < b.min[i] || o > b.max[i] {
+                return None;
+            }
+            continue;
+        }
+        let inv = 1.0 / d;
+        let mut t0 = (b.min[i] - o) * inv;
+        let mut t1 = (b.max[i] - o) * inv;
+        if t0 > t1 {
+            std::mem::swap(&mut t0, &mut t1);
+        }
+        tmin = tmin.max(t0);
+        tmax = tmax.min(t1);
+        if tmin > tmax {
+            return None;
+        }
+    }
+    Some((tmin, tmax))
+}
+
+// Nearest triangle of mesh hit by ray, both in the mesh's object space.
+// Goes through the mesh's BVH when it has one, otherwise tests every
+// triangle after rejecting by the mesh bounds.
+pub fn raycast_mesh(ray: &Ray, mesh: &Mesh) -> Option<RayHit> {
+    if let Some(bvh) = mesh.bvh.as_ref() {
+        return bvh.raycast(ray, mesh);
+    }
+    if !mesh.aabb.is_empty() && ray_aabb(ray, &mesh.aabb).is_none() {
+        return None;
+    }
+    let mut best: Option<RayHit> = None;
+    for i in 0..mesh.triangle_count() {
+        let (a, b, c) = mesh.triangle(i);
+        if let Some((t, u, v)) = ray_triangle(ray, a.pos, b.pos, c.pos) {
+            if best.is_none_or(|h| t < h.t) {
+                best = Some(RayHit { triangle: i as u32, t, barycentric: Vector3::new(1.0 - u - v, u, v) });
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ray_aabb, ray_triangle, screen_ray, unproject_inverse, Ray};
+    use crate::bounds::Aabb;
+    use crate::matrix_calc::Matrix4f;
+    use crate::transform_calc::Transform;
+    use crate::vector_calc::{Vector3, Vector4f};
+
+    fn down_at(x: f32, y: f32) -> Ray {
+        Ray::new(Vector4f::point(x, y, 5.0), Vector4f::direction(0.0, 0.0, -1.0))
+    }
+
+    fn unit_triangle(scale: f32) -> (Vector4f, Vector4f, Vector4f) {
+        (Vector4f::point(0.0, 0.0, 0.0), Vector4f::point(scale, 0.0, 0.0), Vector4f::point(0.0, scale, 0.0))
+    }
+
+    #[test]
+    fn ray_triangle_hit_and_miss() {
+        let (a, b, c) = unit_triangle(1.0);
+        assert_eq!(ray_triangle(&down_at(0.25, 0.5), a, b, c), Some((5.0, 0.25, 0.5)));
+        // winding does not matter
+        assert_eq!(ray_triangle(&down_at(0.25, 0.5), a, c, b), Some((5.0, 0.5, 0.25)));
+        assert_eq!(ray_triangle(&down_at(0.75, 0.75), a, b, c), None);
+        assert_eq!(ray_triangle(&down_at(-0.1, 0.5), a, b, c), None);
+        // behind the origin
+        let up = Ray::new(Vector4f::point(0.25, 0.25, 5.0), Vector4f::direction(0.0, 0.0, 1.0));
+        assert_eq!(ray_triangle(&up, a, b, c), None);
+        // t is in units of dir
+        let slow = Ray::new(Vector4f::point(0.25, 0.25, 5.0), Vector4f::direction(0.0, 0.0, -0.5));
+        assert_eq!(ray_triangle(&slow, a, b, c).map(|h| h.0), Some(10.0));
+    }
+
+    #[test]
+    fn ray_triangle_edges_and_corners_count() {
+        let (a, b, c) = unit_triangle(1.0);
+        assert_eq!(ray_triangle(&down_at(0.5, 0.0), a, b, c), Some((5.0, 0.5, 0.0)));
+        assert_eq!(ray_triangle(&down_at(0.0, 0.5), a, b, c), Some((5.0, 0.0, 0.5)));
+        assert_eq!(ray_triangle(&down_at(0.5, 0.5), a, b, c), Some((5.0, 0.5, 0.5)));
+        assert_eq!(ray_triangle(&down_at(0.0, 0.0), a, b, c), Some((5.0, 0.0, 0.0)));
+        assert_eq!(ray_triangle(&down_at(1.0, 0.0), a, b, c), Some((5.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn ray_triangle_parallel_is_relative_to_scale() {
+        let (a, b, c) = unit_triangle(1.0);
+        let along = Ray::new(Vector4f::point(-1.0, 0.25, 0.0), Vector4f::direction(1.0, 0.0, 0.0));
+        assert_eq!(ray_triangle(&along, a, b, c), None);
+        let above = Ray::new(Vector4f::point(-1.0, 0.25, 1e-3), Vector4f::direction(1.0, 0.0, 0.0));
+        assert_eq!(ray_triangle(&above, a, b, c), None);
+        // tiny triangles and short directions still hit, an absolute
+        // threshold on det would drop them
+        let (a, b, c) = unit_triangle(1e-4);
+        let ray = Ray::new(Vector4f::point(2.5e-5, 2.5e-5, 1.0), Vector4f::direction(0.0, 0.0, -1e-3));
+        let (t, u, v) = ray_triangle(&ray, a, b, c).unwrap();
+        assert!((t - 1000.0).abs() < 1e-2 && (u - 0.25).abs() < 1e-4 && (v - 0.25).abs() < 1e-4);
+        // and so do huge ones
+        let (a, b, c) = unit_triangle(1e5);
+        assert!(ray_triangle(&down_at(2e4, 3e4), a, b, c).is_some());
+    }
+
+    #[test]
+    fn ray_aabb_ranges() {
+        let b = Aabb::from_points(&[Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 2.0, 1.0)]);
+        let ray = Ray::new(Vector4f::point(-5.0, 0.0, 0.0), Vector4f::direction(1.0, 0.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), Some((4.0, 6.0)));
+        let ray = Ray::new(Vector4f::point(-5.0, 0.0, 0.0), Vector4f::direction(2.0, 0.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), Some((2.0, 3.0)));
+        // diagonal through the corner region
+        let ray = Ray::new(Vector4f::point(-3.0, -3.0, 0.0), Vector4f::direction(1.0, 1.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), Some((2.0, 4.0)));
+        // pointing away, or passing beside with a zero component
+        let ray = Ray::new(Vector4f::point(-5.0, 0.0, 0.0), Vector4f::direction(-1.0, 0.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), None);
+        let ray = Ray::new(Vector4f::point(-5.0, 3.0, 0.0), Vector4f::direction(1.0, 0.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), None);
+        assert_eq!(ray_aabb(&ray, &Aabb::empty()), None);
+    }
+
+    #[test]
+    fn ray_aabb_origin_inside() {
+        let b = Aabb::from_points(&[Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0)]);
+        let ray = Ray::new(Vector4f::point(0.0, 0.5, 0.0), Vector4f::direction(0.0, 1.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), Some((0.0, 0.5)));
+        let ray = Ray::new(Vector4f::point(0.0, 0.5, 0.0), Vector4f::direction(0.0, -1.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), Some((0.0, 1.5)));
+        // on the surface, leaving
+        let ray = Ray::new(Vector4f::point(1.0, 0.0, 0.0), Vector4f::direction(1.0, 0.0, 0.0));
+        assert_eq!(ray_aabb(&ray, &b), Some((0.0, 0.0)));
+    }
+
+    #[test]
+    fn unproject_then_project_round_trip() {
+        let (w, h) = (64.0, 48.0);
+        let mut t = Transform::init(64, 48);
+        t.world = Matrix4f::rotation(1.0, 2.0, 0.5, 0.7) * Matrix4f::translation(0.5, -0.25, 0.3);
+        t.view.set_lookat(Vector4f::point(3.0, 1.0, 2.0), Vector4f::point(0.0, 0.0, 0.0), Vector4f::direction(0.0, 0.0, 1.0));
+        t.update();
+        let inv = t.transform.inverted().unwrap();
+        for &(x, y, z) in [(32.0, 24.0, 0.5), (1.5, 2.5, 0.1), (60.0, 40.0, 0.9), (10.0, 45.0, 0.0)].iter() {
+            let p = unproject_inverse(inv, w, h, x, y, z).unwrap();
+            assert_eq!(p.w, 1.0);
+            let mut s = Vector4f::new();
+            t.homogenize(&mut s, p * t.transform);
+            assert!((s.x - x).abs() < 1e-2 && (s.y - y).abs() < 1e-2 && (s.z - z).abs() < 1e-4, "{:?} vs {} {} {}", s, x, y, z);
+            // the pixel's ray passes through it
+            let ray = screen_ray(t.transform, w, h, x, y).unwrap();
+            let along = p - ray.origin;
+            assert!(along.cross(ray.dir).length() < 1e-3 * along.length().max(1.0), "{:?} off {:?}", p, ray);
+        }
+    }
+}
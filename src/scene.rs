@@ -0,0 +1,247 @@
+use crate::bvh::BvhSplit;
+use crate::light::Light;
+use crate::material::Material;
+use crate::matrix_calc::Matrix4f;
+use crate::mesh::Mesh;
+use crate::quaternion_calc::Quaternion;
+use crate::vector_calc::{Vector3, Vector4f};
+
+pub struct Node {
+    pub name: String,
+    pub translation: Vector4f,
+    pub rotation: Quaternion,
+    pub scale: Vector3,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub mesh: Option<usize>,
+    pub light: Option<Light>,
+    pub visible: bool,
+    pub world: Matrix4f, // written by Scene::update_world
+}
+
+impl Node {
+    pub fn new(name: &str) -> Node {
+        Node {
+            name: name.to_string(),
+            translation: Vector4f::point(0.0, 0.0, 0.0),
+            rotation: Quaternion::identity(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+            parent: None,
+            children: Vec::new(),
+            mesh: None,
+            light: None,
+            visible: true,
+            world: Matrix4f::identity(),
+        }
+    }
+
+    // Scale, then rotate, then translate.
+    pub fn local_matrix(&self) -> Matrix4f {
+        let s = Matrix4f::scaling(self.scale.x, self.scale.y, self.scale.z);
+        let t = Matrix4f::translation(self.translation.x, self.translation.y, self.translation.z);
+        s * self.rotation.to_matrix() * t
+    }
+}
+
+pub struct Scene {
+    pub nodes: Vec<Node>,
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<Material>,
+    pub bvh_split: BvhSplit, // how add_mesh builds BVHs for meshes without one
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            nodes: Vec::new(),
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            bvh_split: BvhSplit::Sah,
+        }
+    }
+
+    pub fn add_mesh(&mut self, mut mesh: Mesh) -> usize {
+        mesh.compute_bounds();
+        if mesh.bvh.is_none() {
+            mesh.build_bvh(self.bvh_split);
+        }
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    pub fn add_material(&mut self, material: Material) -> usize {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    pub fn add_node(&mut self, node: Node, parent: Option<usize>) -> usize {
+        let id = self.nodes.len();
+        self.nodes.push(node);
+        self.nodes[id].parent = None;
+        self.nodes[id].children.clear();
+        if let Some(p) = parent {
+            self.set_parent(id, Some(p));
+        }
+        id
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|n| n.name == name)
+    }
+
+    // Returns false if the new parent is the node itself or one of its
+    // descendants, which would make a cycle.
+    pub fn set_parent(&mut self, child: usize, parent: Option<usize>) -> bool {
+        if let Some(p) = parent {
+            let mut cur = Some(p);
+            while let Some(c) = cur {
+                if c == child {
+                    return false;
+                }
+                cur = self.nodes[c].parent;
+            }
+        }
+        if let Some(old) = self.nodes[child].parent {
+            self.nodes[old].children.retain(|&c| c != child);
+        }
+        self.nodes[child].parent = parent;
+        if let Some(p) = parent {
+            self.nodes[p].children.push(child);
+        }
+        true
+    }
+
+    pub fn roots(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|&i| self.nodes[i].parent.is_none()).collect()
+    }
+
+    // Parents are always visited before their children.
+    pub fn update_world(&mut self) {
+        let mut stack: Vec<(usize, Matrix4f)> = self.roots().iter()
+            .map(|&r| (r, Matrix4f::identity()))
+            .collect();
+        while let Some((id, parent_world)) = stack.pop() {
+            let world = self.nodes[id].local_matrix() * parent_world;
+            self.nodes[id].world = world;
+            for &c in self.nodes[id].children.iter() {
+                stack.push((c, world));
+            }
+        }
+    }
+
+    // Nodes whose whole ancestor chain is visible, parents before children.
+    fn visible_nodes(&self) -> Vec<usize> {
+        let mut ret = Vec::new();
+        let mut stack = self.roots();
+        while let Some(id) = stack.pop() {
+            let node = &self.nodes[id];
+            if !node.visible {
+                continue;
+            }
+            ret.push(id);
+            stack.extend(node.children.iter().rev());
+        }
+        ret
+    }
+
+    // Visible (node, mesh) pairs; hiding a node hides its whole subtree.
+    pub fn drawables(&self) -> Vec<(usize, usize)> {
+        self.visible_nodes().into_iter()
+            .filter_map(|id| self.nodes[id].mesh.map(|m| (id, m)))
+            .collect()
+    }
+
+    // Visible lights with position and direction moved into world space.
+    pub fn lights(&self) -> Vec<Light> {
+        let mut ret = Vec::new();
+        for id in self.visible_nodes() {
+            let node = &self.nodes[id];
+            if let Some(light) = node.light {
+                let mut l = light;
+                l.position = Vector4f::point(0.0, 0.0, 0.0) * node.world;
+                l.direction = (Vector4f::direction(0.0, 0.0, 1.0) * node.world).normalized();
+                ret.push(l);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, Scene};
+    use crate::calc::Color;
+    use crate::light::Light;
+    use crate::mesh::Mesh;
+    use crate::vector_calc::Vector4f;
+
+    fn at(name: &str, x: f32, y: f32, z: f32) -> Node {
+        let mut node = Node::new(name);
+        node.translation = Vector4f::point(x, y, z);
+        node
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let mut scene = Scene::new();
+        let a = scene.add_node(Node::new("a"), None);
+        let b = scene.add_node(Node::new("b"), Some(a));
+        let c = scene.add_node(Node::new("c"), Some(b));
+        assert!(!scene.set_parent(a, Some(a)));
+        assert!(!scene.set_parent(a, Some(c)));
+        assert!(!scene.set_parent(b, Some(c)));
+        assert_eq!(scene.nodes[a].parent, None);
+        assert_eq!(scene.nodes[b].children, vec![c]);
+        // moving c up to a is fine and leaves b childless
+        assert!(scene.set_parent(c, Some(a)));
+        assert_eq!(scene.nodes[a].children, vec![b, c]);
+        assert!(scene.nodes[b].children.is_empty());
+        assert_eq!(scene.roots(), vec![a]);
+    }
+
+    #[test]
+    fn update_world_applies_parents_first() {
+        let mut scene = Scene::new();
+        // children created before their parents, so index order is wrong
+        let leaf = scene.add_node(at("leaf", 0.0, 0.0, 1.0), None);
+        let mid = scene.add_node(at("mid", 0.0, 2.0, 0.0), None);
+        let root = scene.add_node(at("root", 3.0, 0.0, 0.0), None);
+        assert!(scene.set_parent(leaf, Some(mid)));
+        assert!(scene.set_parent(mid, Some(root)));
+        scene.nodes[root].scale.x = 2.0;
+        scene.update_world();
+        let origin = Vector4f::point(0.0, 0.0, 0.0);
+        assert_eq!(origin * scene.nodes[leaf].world, Vector4f::point(3.0, 2.0, 1.0));
+        assert_eq!(Vector4f::point(1.0, 0.0, 0.0) * scene.nodes[leaf].world, Vector4f::point(5.0, 2.0, 1.0));
+        assert_eq!(origin * scene.nodes[mid].world, Vector4f::point(3.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn hiding_a_node_hides_its_subtree() {
+        let mut scene = Scene::new();
+        let mesh = scene.add_mesh(Mesh::cube());
+        let mut with_mesh = |name: &str, parent: Option<usize>| {
+            let mut node = Node::new(name);
+            node.mesh = Some(mesh);
+            scene.add_node(node, parent)
+        };
+        let a = with_mesh("a", None);
+        let b = with_mesh("b", Some(a));
+        let c = with_mesh("c", Some(b));
+        let d = with_mesh("d", None);
+        let mut lamp = Node::new("lamp");
+        lamp.light = Some(Light::point(Color::new(1.0, 1.0, 1.0), 1.0, 5.0));
+        scene.add_node(lamp, Some(c));
+        scene.update_world();
+        assert_eq!(scene.drawables().len(), 4);
+        assert_eq!(scene.lights().len(), 1);
+        scene.nodes[b].visible = false;
+        let mut drawn: Vec<usize> = scene.drawables().iter().map(|&(node, _)| node).collect();
+        drawn.sort();
+        assert_eq!(drawn, vec![a, d]);
+        assert!(scene.lights().is_empty());
+        scene.nodes[b].visible = true;
+        assert!(scene.drawables().iter().any(|&(node, _)| node == c));
+        assert_eq!(scene.lights().len(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+use std::f32::consts::PI;
+use crate::calc::Color;
+use crate::light::{Light, LightKind};
+use crate::texture::{Texture, TextureFormat};
+use crate::calc::CMID;
+use crate::vector_calc::{Vector3, Vector4f};
+
+pub fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge0 == edge1 {
+        return match x < edge0 {
+            true => { 0.0 }
+            false => { 1.0 }
+        };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Inverse-square falloff windowed to reach exactly zero at range.
+pub fn range_attenuation(dist: f32, range: f32) -> f32 {
+    let falloff = 1.0 / (dist * dist + 1.0);
+    if range <= 0.0 {
+        return falloff;
+    }
+    let r = dist / range;
+    let window = (1.0 - r * r * r * r).clamp(0.0, 1.0);
+    window * window * falloff
+}
+
+// Unit direction from p towards the light and the light's intensity arriving
+// at p, both in world space.
+pub fn light_incidence(light: &Light, p: Vector4f) -> (Vector4f, f32) {
+    match light.kind {
+        LightKind::Directional => {
+            (-light.direction.normalized(), light.intensity)
+        }
+        LightKind::Point | LightKind::Spot => {
+            let mut d = light.position - p;
+            d.w = 0.0;
+            let dist = d.length();
+            if dist == 0.0 {
+                return (Vector4f::direction(0.0, 0.0, 1.0), 0.0);
+            }
+            let l = d * (1.0 / dist);
+            let mut intensity = light.intensity * range_attenuation(dist, light.range);
+            if light.kind == LightKind::Spot {
+                let cos = (-l).dot(light.direction.normalized());
+                intensity *= smoothstep(light.outer_angle.cos(), light.inner_angle.cos(), cos);
+            }
+            (l, intensity)
+        }
+    }
+}
+
+// Tangent-space normal stored in an 0x00RRGGBB texel, each channel mapping
+// 0..255 to -1..1 with +Z pointing away from the surface.
+pub fn decode_normal(texel: u32) -> Vector3 {
+    let x = ((texel >> 16) & 0xFF) as f32 / 255.0 * 2.0 - 1.0;
+    let y = ((texel >> 8) & 0xFF) as f32 / 255.0 * 2.0 - 1.0;
+    let z = (texel & 0xFF) as f32 / 255.0 * 2.0 - 1.0;
+    Vector3::new(x, y, z).normalized()
+}
+
+pub fn encode_normal(n: Vector3) -> u32 {
+    let c = |f: f32| CMID(((f * 0.5 + 0.5) * 255.0 + 0.5) as i32, 0, 255) as u32;
+    (c(n.x) << 16) | (c(n.y) << 8) | c(n.z)
+}
+
+// Normal map from a [y][x] height field using central differences; u runs
+// along x and v along y, matching texture_read.
+pub fn normal_map_from_height(height: &[Vec<f32>], strength: f32) -> Texture {
+    let h = height.len();
+    let w = match h {
+        0 => { 0 }
+        _ => { height[0].len() }
+    };
+    let mut ret = Texture::new(w, h, TextureFormat::Rgb8);
+    for y in 0..h {
+        for x in 0..w {
+            let hx = height[y][(x + 1) % w] - height[y][(x + w - 1) % w];
+            let hy = height[(y + 1) % h][x] - height[(y + h - 1) % h][x];
+            let n = Vector3::new(-hx * strength, -hy * strength, 1.0).normalized();
+            ret.set(x, y, encode_normal(n));
+        }
+    }
+    ret
+}
+
+// Moves a tangent-space normal into the space of n and t (t.w holds the
+// bitangent sign). Falls back to n when there is no usable tangent.
+pub fn perturb_normal(n: Vector4f, t: Vector4f, tn: Vector3) -> Vector4f {
+    let sign = match t.w < 0.0 {
+        true => { -1.0 }
+        false => { 1.0 }
+    };
+    let n = n.normalized();
+    let mut t = t - n * n.dot(t);
+    t.w = 0.0;
+    if t.length() < 1e-6 {
+        return n;
+    }
+    t.normalize();
+    let b = n.cross(t) * sign;
+    let ret = t * tn.x + b * tn.y + n * tn.z;
+    Vector4f::direction(ret.x, ret.y, ret.z).normalized()
+}
+
+// Mirror of incident direction i about unit normal n.
+pub fn reflect(i: Vector4f, n: Vector4f) -> Vector4f {
+    i - n * (2.0 * n.dot(i))
+}
+
+// Snell refraction of unit i through unit n with eta = n1 / n2; None on
+// total internal reflection.
+pub fn refract(i: Vector4f, n: Vector4f, eta: f32) -> Option<Vector4f> {
+    let cos_i = -n.dot(i);
+    let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+    if k < 0.0 {
+        return None;
+    }
+    Some(i * eta + n * (eta * cos_i - k.sqrt()))
+}
+
+// Sphere map coordinates for a view-space reflection vector. The camera
+// looks along +Z here, so the map's centre faces -Z.
+pub fn sphere_map_uv(r: Vector4f) -> (f32, f32) {
+    let z = 1.0 - r.z;
+    let m = 2.0 * (r.x * r.x + r.y * r.y + z * z).sqrt();
+    if m == 0.0 {
+        return (0.5, 0.5);
+    }
+    (r.x / m + 0.5, 0.5 - r.y / m)
+}
+
+// Cook-Torrance with a GGX distribution, Smith-Schlick geometry and Schlick
+// Fresnel, plus Lambert diffuse for the non-metal part. Returns reflected
+// light per unit radiance already multiplied by n.l; n, v and l are unit
+// vectors pointing away from the surface. Light intensities are taken as
+// pi-scaled so a white Lambert surface facing a unit light reads 1, as in
+// the Blinn-Phong path.
+pub fn cook_torrance(n: Vector4f, v: Vector4f, l: Vector4f, albedo: Color, metallic: f32, roughness: f32) -> Color {
+    let n_dot_l = n.dot(l).max(0.0);
+    let n_dot_v = n.dot(v).max(1e-4);
+    if n_dot_l <= 0.0 {
+        return Color::default();
+    }
+    let h = (v + l).normalized();
+    let n_dot_h = n.dot(h).max(0.0);
+    let v_dot_h = v.dot(h).max(0.0);
+    // perceptual roughness squared, kept off zero so highlights stay finite
+    let a = (roughness * roughness).max(1e-3);
+    let a2 = a * a;
+    let denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    let d = a2 / (PI * denom * denom);
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let g = (n_dot_v / (n_dot_v * (1.0 - k) + k)) * (n_dot_l / (n_dot_l * (1.0 - k) + k));
+    let f0 = Color::new(0.04, 0.04, 0.04).lerp(albedo, metallic);
+    let f = f0 + (Color::new(1.0, 1.0, 1.0) - f0) * (1.0 - v_dot_h).powi(5);
+    let specular = f * (d * g / (4.0 * n_dot_v * n_dot_l));
+    let kd = (Color::new(1.0, 1.0, 1.0) - f) * (1.0 - metallic);
+    let diffuse = kd * albedo * (1.0 / PI);
+    (diffuse + specular) * (n_dot_l * PI)
+}
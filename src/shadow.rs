@@ -0,0 +1,180 @@
+use crate::bounds::Aabb;
+use crate::buffer::Buffer2D;
+use crate::calc::{trapezoid_init, trapezoid_init_triangle, trapezoid_scan, Trapezoid};
+use crate::light::{Light, LightKind};
+use crate::matrix_calc::Matrix4f;
+use crate::mesh::Mesh;
+use crate::vector_calc::Vector4f;
+use crate::vertex::Vertex;
+
+// Depth from a light's point of view. Depth is post-projection z / w in 0..1
+// and 1.0 means nothing was drawn there.
+pub struct ShadowMap {
+    pub size: usize,
+    pub depth: Buffer2D<f32>,
+    pub view: Matrix4f,
+    pub projection: Matrix4f,
+    pub view_proj: Matrix4f,
+    pub bias: f32,
+    pub slope_bias: f32,
+    pub pcf_radius: i32,
+}
+
+impl ShadowMap {
+    pub fn new(size: usize) -> ShadowMap {
+        ShadowMap {
+            size,
+            depth: Buffer2D::new(size, size, 1.0),
+            view: Matrix4f::identity(),
+            projection: Matrix4f::identity(),
+            view_proj: Matrix4f::identity(),
+            bias: 0.002,
+            slope_bias: 0.01,
+            pcf_radius: 1,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.depth.fill(1.0);
+    }
+
+    fn look_along(&mut self, eye: Vector4f, dir: Vector4f) {
+        let up = match dir.normalized().z.abs() > 0.99 {
+            true => { Vector4f::direction(0.0, 1.0, 0.0) }
+            false => { Vector4f::direction(0.0, 0.0, 1.0) }
+        };
+        self.view = Matrix4f::lookat(eye, eye + dir, up);
+    }
+
+    // Orthographic box along the light direction enclosing `bounds` (world space).
+    pub fn setup_directional(&mut self, light: &Light, bounds: Aabb) {
+        let center = bounds.center();
+        let radius = bounds.extents().length().max(1e-3);
+        let dir = light.direction.normalized();
+        let eye = center.to_point() - dir * (radius * 2.0);
+        self.look_along(eye, dir);
+        self.projection = Matrix4f::orthographic(-radius, radius, -radius, radius, radius * 0.5, radius * 3.5);
+        self.view_proj = self.view * self.projection;
+    }
+
+    pub fn setup_spot(&mut self, light: &Light, z_near: f32) {
+        let range = match light.range > 0.0 {
+            true => { light.range }
+            false => { 100.0 }
+        };
+        self.look_along(light.position, light.direction);
+        let fov = (light.outer_angle * 2.0).min(3.0);
+        self.projection = Matrix4f::perspective(fov, 1.0, z_near, range);
+        self.view_proj = self.view * self.projection;
+    }
+
+    // Returns false for kinds without a shadow projection (point lights).
+    pub fn setup(&mut self, light: &Light, scene_bounds: Aabb) -> bool {
+        match light.kind {
+            LightKind::Directional => { self.setup_directional(light, scene_bounds); true }
+            LightKind::Spot => { self.setup_spot(light, 0.1); true }
+            LightKind::Point => { false }
+        }
+    }
+
+    pub fn render_mesh(&mut self, mesh: &Mesh, world: Matrix4f) {
+        let m = world * self.view_proj;
+        for i in 0..mesh.triangle_count() {
+            let (a, b, c) = mesh.triangle(i);
+            self.draw_triangle(a.pos * m, b.pos * m, c.pos * m);
+        }
+    }
+
+    fn draw_triangle(&mut self, c1: Vector4f, c2: Vector4f, c3: Vector4f) {
+        // Partially visible triangles are kept and clipped by the raster bounds;
+        // only those behind the light or wholly outside one plane are dropped.
+        if c1.w <= 1e-5 || c2.w <= 1e-5 || c3.w <= 1e-5 {
+            return;
+        }
+        for axis in 0..3 {
+            if c1[axis] > c1.w && c2[axis] > c2.w && c3[axis] > c3.w {
+                return;
+            }
+            let lo = |c: Vector4f| match axis {
+                2 => { c.z < 0.0 }
+                _ => { c[axis] < -c.w }
+            };
+            if lo(c1) && lo(c2) && lo(c3) {
+                return;
+            }
+        }
+        let size = self.size as f32;
+        let mut t = [Vertex::new(); 3];
+        for (v, c) in t.iter_mut().zip([c1, c2, c3].iter()) {
+            let rhw = 1.0 / c.w;
+            v.pos = Vector4f {
+                x: (c.x * rhw + 1.0) * size * 0.5,
+                y: (1.0 - c.y * rhw) * size * 0.5,
+                z: c.z * rhw,
+                w: c.w,
+            };
+            v.rhw_init();
+        }
+        let traps: &mut [Trapezoid; 2] = &mut [trapezoid_init(); 2];
+        let n;
+        unsafe {
+            n = trapezoid_init_triangle(traps, t[0], t[1], t[2]);
+        }
+        for trap in traps.iter_mut().take(n as usize) {
+            self.render_trap(trap);
+        }
+    }
+
+    fn render_trap(&mut self, trap: &mut Trapezoid) {
+        let depth = &mut self.depth;
+        trapezoid_scan(trap, self.size as i32, |mut scanline| {
+            let row = depth.row_mut(scanline.y as usize);
+            for x in scanline.x..scanline.x + scanline.w {
+                if x >= 0 && (x as usize) < row.len() {
+                    let z = scanline.v.pos.z;
+                    if z >= 0.0 && z < row[x as usize] {
+                        row[x as usize] = z;
+                    }
+                }
+                scanline.v.add(scanline.step);
+            }
+        });
+    }
+
+    fn sample(&self, x: i32, y: i32, depth: f32) -> f32 {
+        if x < 0 || y < 0 || x >= self.size as i32 || y >= self.size as i32 {
+            return 1.0;
+        }
+        match depth <= self.depth[(x as usize, y as usize)] {
+            true => { 1.0 }
+            false => { 0.0 }
+        }
+    }
+
+    // Fraction of the (2 * pcf_radius + 1)^2 neighbourhood that sees the light
+    // from world position p. n_dot_l scales the slope bias.
+    pub fn visibility(&self, p: Vector4f, n_dot_l: f32) -> f32 {
+        let c = Vector4f { w: 1.0, ..p } * self.view_proj;
+        if c.w <= 0.0 {
+            return 1.0;
+        }
+        let rhw = 1.0 / c.w;
+        let z = c.z * rhw;
+        if z > 1.0 {
+            return 1.0;
+        }
+        let size = self.size as f32;
+        let x = ((c.x * rhw + 1.0) * size * 0.5).floor() as i32;
+        let y = ((1.0 - c.y * rhw) * size * 0.5).floor() as i32;
+        let bias = self.bias + self.slope_bias * (1.0 - n_dot_l.clamp(0.0, 1.0));
+        let r = self.pcf_radius.max(0);
+        let mut lit = 0.0;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                lit += self.sample(x + dx, y + dy, z - bias);
+            }
+        }
+        let n = (2 * r + 1) * (2 * r + 1);
+        lit / n as f32
+    }
+}
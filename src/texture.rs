@@ -0,0 +1,417 @@
+use std::io;
+use crate::calc::{Color, CMID};
+use crate::pixel::{linear_to_srgb, srgb_to_linear};
+
+// How texels are packed into each u32.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureFormat {
+    Rgb8,  // 0x00RRGGBB, same as the framebuffer
+    Rgba8, // 0xAARRGGBB
+    Gray8, // 0x000000LL
+}
+
+// What sampling does with u and v outside 0..1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureAddress {
+    Clamp,  // edge texels stretch outwards
+    Repeat, // the image tiles, u and u + 1 sample the same texel
+}
+
+// Row-major texture with a mip chain. mips[0] is the full-size image and
+// every further level halves both sides (rounding down, at least 1). srgb
+// marks color data stored gamma encoded; data maps like normals stay linear.
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub format: TextureFormat,
+    pub srgb: bool,
+    pub address: TextureAddress,
+    pub mips: Vec<Vec<u32>>,
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, format: TextureFormat) -> Texture {
+        let width = width.max(1);
+        let height = height.max(1);
+        Texture {
+            width,
+            height,
+            format,
+            srgb: false,
+            address: TextureAddress::Clamp,
+            mips: vec![vec![0; width * height]],
+        }
+    }
+
+    // Takes [y][x] rows as the old Device::texture field held them.
+    pub fn from_rows(rows: &[Vec<u32>], format: TextureFormat) -> Texture {
+        let height = rows.len();
+        let width = match height {
+            0 => { 0 }
+            _ => { rows[0].len() }
+        };
+        let mut ret = Texture::new(width, height, format);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate().take(ret.width) {
+                ret.mips[0][y * ret.width + x] = *texel;
+            }
+        }
+        ret
+    }
+
+    pub fn checkerboard(size: usize, cell: usize, c1: u32, c2: u32) -> Texture {
+        let mut ret = Texture::new(size, size, TextureFormat::Rgb8);
+        ret.srgb = true;
+        ret.address = TextureAddress::Repeat;
+        let cell = cell.max(1);
+        for j in 0..size {
+            for i in 0..size {
+                ret.mips[0][j * size + i] = match (i / cell + j / cell) & 1 > 0 {
+                    true => { c1 }
+                    false => { c2 }
+                };
+            }
+        }
+        ret
+    }
+
+    // Binary (P6) or ASCII (P3) PPM, the one image format we read without
+    // extra dependencies. Loaded as sRGB color.
+    pub fn from_ppm(bytes: &[u8]) -> io::Result<Texture> {
+        let bad = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("ppm: {}", msg));
+        let mut pos = 0;
+        let mut header = Vec::new();
+        // magic, width, height and maxval, skipping whitespace and comments
+        while header.len() < 4 {
+            while pos < bytes.len() && (bytes[pos].is_ascii_whitespace() || bytes[pos] == b'#') {
+                if bytes[pos] == b'#' {
+                    while pos < bytes.len() && bytes[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+            let start = pos;
+            while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(bad("truncated header"));
+            }
+            header.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+        }
+        let binary = match header[0].as_str() {
+            "P6" => { true }
+            "P3" => { false }
+            _ => { return Err(bad("not a P3/P6 file")); }
+        };
+        let num = |s: &String| s.parse::<usize>().map_err(|_| bad("bad number in header"));
+        let (width, height, maxval) = (num(&header[1])?, num(&header[2])?, num(&header[3])?);
+        if width == 0 || height == 0 || maxval == 0 || maxval > 65535 {
+            return Err(bad("bad dimensions"));
+        }
+        let count = width * height * 3;
+        let samples: Vec<usize> = match binary {
+            true => {
+                // a single whitespace byte separates the header from the data
+                let data = &bytes[(pos + 1).min(bytes.len())..];
+                let wide = maxval > 255;
+                let step = match wide {
+                    true => { 2 }
+                    false => { 1 }
+                };
+                if data.len() < count * step {
+                    return Err(bad("truncated pixel data"));
+                }
+                (0..count).map(|i| match wide {
+                    true => { ((data[i * 2] as usize) << 8) | data[i * 2 + 1] as usize }
+                    false => { data[i] as usize }
+                }).collect()
+            }
+            false => {
+                let text = String::from_utf8_lossy(&bytes[pos..]);
+                let values: Result<Vec<usize>, _> = text.split_ascii_whitespace().take(count).map(|v| v.parse::<usize>()).collect();
+                let values = values.map_err(|_| bad("bad pixel value"))?;
+                if values.len() < count {
+                    return Err(bad("truncated pixel data"));
+                }
+                values
+            }
+        };
+        let mut ret = Texture::new(width, height, TextureFormat::Rgb8);
+        ret.srgb = true;
+        for (i, texel) in ret.mips[0].iter_mut().enumerate() {
+            let c = |k: usize| (samples[i * 3 + k].min(maxval) * 255 / maxval) as u32;
+            *texel = (c(0) << 16) | (c(1) << 8) | c(2);
+        }
+        Ok(ret)
+    }
+
+    pub fn load_ppm(path: &str) -> io::Result<Texture> {
+        Texture::from_ppm(&std::fs::read(path)?)
+    }
+
+    pub fn levels(&self) -> usize {
+        self.mips.len()
+    }
+
+    pub fn level_size(&self, level: usize) -> (usize, usize) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u32 {
+        self.mips[0][y * self.width + x]
+    }
+
+    // Edits level 0 only; call generate_mips() afterwards if mips are in use.
+    pub fn set(&mut self, x: usize, y: usize, texel: u32) {
+        self.mips[0][y * self.width + x] = texel;
+    }
+
+    fn unpack(&self, texel: u32) -> [u32; 4] {
+        match self.format {
+            TextureFormat::Rgb8 => { [(texel >> 16) & 0xFF, (texel >> 8) & 0xFF, texel & 0xFF, 0xFF] }
+            TextureFormat::Rgba8 => { [(texel >> 16) & 0xFF, (texel >> 8) & 0xFF, texel & 0xFF, texel >> 24] }
+            TextureFormat::Gray8 => { [texel & 0xFF, texel & 0xFF, texel & 0xFF, 0xFF] }
+        }
+    }
+
+    fn pack(&self, c: [u32; 4]) -> u32 {
+        match self.format {
+            TextureFormat::Rgb8 => { (c[0] << 16) | (c[1] << 8) | c[2] }
+            TextureFormat::Rgba8 => { (c[3] << 24) | (c[0] << 16) | (c[1] << 8) | c[2] }
+            TextureFormat::Gray8 => { c[0] }
+        }
+    }
+
+    // Rebuilds every level below 0 with a 2x2 box filter. sRGB color is
+    // averaged in linear space so mips do not darken; alpha is always linear.
+    pub fn generate_mips(&mut self) {
+        self.mips.truncate(1);
+        let mut level = 0;
+        while self.level_size(level) != (1, 1) {
+            let (sw, sh) = self.level_size(level);
+            let (dw, dh) = self.level_size(level + 1);
+            let mut next = vec![0; dw * dh];
+            for y in 0..dh {
+                for x in 0..dw {
+                    let mut sum = [0.0f32; 4];
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                        let sx = (x * 2 + dx).min(sw - 1);
+                        let sy = (y * 2 + dy).min(sh - 1);
+                        let c = self.unpack(self.mips[level][sy * sw + sx]);
+                        for k in 0..4 {
+                            sum[k] += match self.srgb && k < 3 {
+                                true => { srgb_to_linear(c[k] as f32 / 255.0) }
+                                false => { c[k] as f32 }
+                            };
+                        }
+                    }
+                    let mut avg = [0u32; 4];
+                    for k in 0..4 {
+                        avg[k] = match self.srgb && k < 3 {
+                            true => { (linear_to_srgb(sum[k] * 0.25) * 255.0 + 0.5) as u32 }
+                            false => { (sum[k] * 0.25 + 0.5) as u32 }
+                        };
+                    }
+                    next[y * dw + x] = self.pack(avg);
+                }
+            }
+            self.mips.push(next);
+            level += 1;
+        }
+    }
+
+    // Texel as 0x00RRGGBB whatever the storage format.
+    pub fn texel_rgb(&self, level: usize, x: usize, y: usize) -> u32 {
+        let (w, _) = self.level_size(level);
+        let c = self.unpack(self.mips[level][y * w + x]);
+        (c[0] << 16) | (c[1] << 8) | c[2]
+    }
+
+    pub fn texel_alpha(&self, level: usize, x: usize, y: usize) -> f32 {
+        let (w, _) = self.level_size(level);
+        self.unpack(self.mips[level][y * w + x])[3] as f32 / 255.0
+    }
+
+    // Nearest texel as 0x00RRGGBB, u and v addressed as self.address says.
+    pub fn sample_level(&self, u: f32, v: f32, level: usize) -> u32 {
+        let level = level.min(self.mips.len() - 1);
+        let (w, h) = self.level_size(level);
+        let (x, y) = match self.address {
+            TextureAddress::Clamp => {
+                (CMID((u * (w - 1) as f32 + 0.5) as i32, 0, w as i32 - 1),
+                 CMID((v * (h - 1) as f32 + 0.5) as i32, 0, h as i32 - 1))
+            }
+            TextureAddress::Repeat => {
+                (((u * w as f32).floor() as i32).rem_euclid(w as i32),
+                 ((v * h as f32).floor() as i32).rem_euclid(h as i32))
+            }
+        };
+        self.texel_rgb(level, x as usize, y as usize)
+    }
+
+    // Mip level for a pixel that covers `footprint` of the 0..1 uv range
+    // along its longer screen axis; 0 for magnification.
+    pub fn lod(&self, footprint: f32) -> usize {
+        let texels = footprint * self.width.max(self.height) as f32;
+        if texels.is_nan() || texels <= 1.0 {
+            return 0;
+        }
+        (texels.log2().floor() as usize).min(self.mips.len() - 1)
+    }
+
+    pub fn sample(&self, u: f32, v: f32) -> u32 {
+        self.sample_level(u, v, 0)
+    }
+
+    pub fn sample_color(&self, u: f32, v: f32) -> Color {
+        Color::from_u32(self.sample(u, v))
+    }
+}
+
+// Generational handle: a handle to a removed texture stays invalid even after
+// its slot is reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TextureHandle {
+    index: usize,
+    generation: u32,
+}
+
+struct TextureSlot {
+    generation: u32,
+    texture: Option<Texture>,
+}
+
+pub struct TextureRegistry {
+    slots: Vec<TextureSlot>,
+    free: Vec<usize>,
+}
+
+impl TextureRegistry {
+    pub fn new() -> TextureRegistry {
+        TextureRegistry {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, texture: Texture) -> TextureHandle {
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index];
+            slot.texture = Some(texture);
+            return TextureHandle { index, generation: slot.generation };
+        }
+        self.slots.push(TextureSlot { generation: 0, texture: Some(texture) });
+        TextureHandle { index: self.slots.len() - 1, generation: 0 }
+    }
+
+    pub fn get(&self, handle: TextureHandle) -> Option<&Texture> {
+        match self.slots.get(handle.index) {
+            Some(slot) if slot.generation == handle.generation => { slot.texture.as_ref() }
+            _ => { None }
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: TextureHandle) -> Option<&mut Texture> {
+        match self.slots.get_mut(handle.index) {
+            Some(slot) if slot.generation == handle.generation => { slot.texture.as_mut() }
+            _ => { None }
+        }
+    }
+
+    pub fn contains(&self, handle: TextureHandle) -> bool {
+        self.get(handle).is_some()
+    }
+
+    // Frees the texture and hands it back; stale handles return None.
+    pub fn remove(&mut self, handle: TextureHandle) -> Option<Texture> {
+        if !self.contains(handle) {
+            return None;
+        }
+        let slot = &mut self.slots[handle.index];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        slot.texture.take()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Texture, TextureAddress, TextureFormat};
+
+    fn ramp(width: usize) -> Texture {
+        let mut ret = Texture::new(width, 1, TextureFormat::Rgb8);
+        for x in 0..width {
+            ret.set(x, 0, x as u32 + 1);
+        }
+        ret
+    }
+
+    #[test]
+    fn clamp_stretches_edge_texels() {
+        let tex = ramp(4);
+        assert_eq!(tex.sample(0.0, 0.5), 1);
+        assert_eq!(tex.sample(1.0, 0.5), 4);
+        assert_eq!(tex.sample(-0.3, 0.5), 1);
+        assert_eq!(tex.sample(1.3, 0.5), 4);
+    }
+
+    #[test]
+    fn repeat_tiles() {
+        let mut tex = ramp(4);
+        tex.address = TextureAddress::Repeat;
+        for &u in [0.1, 0.3, 0.6, 0.9].iter() {
+            assert_eq!(tex.sample(u + 1.0, 0.5), tex.sample(u, 0.5));
+            assert_eq!(tex.sample(u - 1.0, 0.5), tex.sample(u, 0.5));
+            assert_eq!(tex.sample(u, 7.5), tex.sample(u, 0.5));
+        }
+        assert_eq!(tex.sample(0.1, 0.5), 1);
+        assert_eq!(tex.sample(0.9, 0.5), 4);
+        // just past the seam wraps to the first texel, just before it the last
+        assert_eq!(tex.sample(1.05, 0.5), 1);
+        assert_eq!(tex.sample(-0.05, 0.5), 4);
+    }
+
+    // Half black, half white: mid gray in linear light, which sRGB stores
+    // as 188 rather than 128.
+    fn half_and_half(format: TextureFormat, srgb: bool) -> Texture {
+        let mut ret = Texture::new(2, 2, format);
+        ret.srgb = srgb;
+        ret.set(0, 0, 0xffffffff);
+        ret.set(1, 1, 0xffffffff);
+        ret.generate_mips();
+        ret
+    }
+
+    #[test]
+    fn srgb_mips_average_in_linear_space() {
+        let tex = half_and_half(TextureFormat::Rgb8, true);
+        assert_eq!(tex.levels(), 2);
+        assert_eq!(tex.texel_rgb(1, 0, 0), 0xbcbcbc);
+        let tex = half_and_half(TextureFormat::Rgb8, false);
+        assert_eq!(tex.texel_rgb(1, 0, 0), 0x808080);
+        // alpha is coverage, not color, and averages linearly either way
+        let tex = half_and_half(TextureFormat::Rgba8, true);
+        assert_eq!(tex.texel_rgb(1, 0, 0), 0xbcbcbc);
+        assert_eq!(tex.mips[1][0] >> 24, 0x80);
+        let tex = half_and_half(TextureFormat::Gray8, true);
+        assert_eq!(tex.mips[1][0], 0xbc);
+    }
+
+    #[test]
+    fn srgb_mips_keep_flat_colors() {
+        let mut tex = Texture::checkerboard(8, 8, 0, 0x3fbcef);
+        tex.generate_mips();
+        assert_eq!(tex.levels(), 4);
+        for level in 1..tex.levels() {
+            assert_eq!(tex.texel_rgb(level, 0, 0), 0x3fbcef);
+        }
+    }
+}
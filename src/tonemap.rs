@@ -0,0 +1,58 @@
+use crate::calc::Color;
+
+// Operators for mapping linear HDR color into 0..1 for display.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMap {
+    Clamp,    // no curve, just the exposure scale
+    Reinhard, // c / (1 + c)
+    Aces,     // Narkowicz's fit of the ACES reference transform
+    Filmic,   // Hable's Uncharted 2 curve
+}
+
+impl ToneMap {
+    pub fn next(&self) -> ToneMap {
+        match self {
+            ToneMap::Clamp => { ToneMap::Reinhard }
+            ToneMap::Reinhard => { ToneMap::Aces }
+            ToneMap::Aces => { ToneMap::Filmic }
+            ToneMap::Filmic => { ToneMap::Clamp }
+        }
+    }
+}
+
+fn reinhard(x: f32) -> f32 {
+    x / (1.0 + x)
+}
+
+fn aces(x: f32) -> f32 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+// Linear white point of the filmic curve.
+const FILMIC_WHITE: f32 = 11.2;
+
+fn filmic(x: f32) -> f32 {
+    // the curve's usual pre-scale, normalized so FILMIC_WHITE maps to 1
+    hable(x * 2.0) / hable(FILMIC_WHITE)
+}
+
+// Exposure is a linear multiplier applied before the curve.
+pub fn tone_map(op: ToneMap, c: Color, exposure: f32) -> Color {
+    let f: fn(f32) -> f32 = match op {
+        ToneMap::Clamp => { |x| x }
+        ToneMap::Reinhard => { reinhard }
+        ToneMap::Aces => { aces }
+        ToneMap::Filmic => { filmic }
+    };
+    let c = c * exposure;
+    Color {
+        r: f(c.r.max(0.0)).min(1.0),
+        g: f(c.g.max(0.0)).min(1.0),
+        b: f(c.b.max(0.0)).min(1.0),
+    }
+}
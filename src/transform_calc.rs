@@ -1,7 +1,6 @@
-use std::f32::consts::PI;
+use std::f32::consts::FRAC_PI_2;
 use crate::matrix_calc::Matrix4f;
 use crate::vector_calc::Vector4f;
-use crate::{HEIGHT, WIDTH};
 
 #[derive(Clone, Copy)]
 pub struct Transform {
@@ -9,6 +8,8 @@ pub struct Transform {
     pub view: Matrix4f,
     pub projection: Matrix4f,
     pub transform: Matrix4f,
+    pub normal: Matrix4f, // inverse-transpose of world, for normals
+    pub eye: Vector4f,    // camera position in world space
     pub w: f32,
     pub h: f32,
 }
@@ -23,22 +24,30 @@ impl Transform {
         };
         m.mul(self.world, self.view);
         self.transform.mul(m, self.projection);
+        self.normal = self.world.to_normal_matrix().unwrap_or(Matrix4f::identity());
+        if let Some(inv) = self.view.inverted_affine() {
+            self.eye = Vector4f::point(inv.m[3][0], inv.m[3][1], inv.m[3][2]);
+        }
     }
 
-    pub fn init() -> Transform {
-        let aspect = WIDTH as f32 / HEIGHT as f32;
+    // Identity world and view, 90 degree perspective for a width x height
+    // target.
+    pub fn init(width: usize, height: usize) -> Transform {
+        let aspect = width as f32 / height as f32;
         let mut ret = Transform {
             world: Matrix4f::new(),
             view: Matrix4f::new(),
             projection: Matrix4f::new(),
             transform: Matrix4f::new(),
-            w: WIDTH as f32,
-            h: HEIGHT as f32,
+            normal: Matrix4f::new(),
+            eye: Vector4f::point(0.0, 0.0, 0.0),
+            w: width as f32,
+            h: height as f32,
        };
         ret.world.set_identity();
         ret.view.set_identity();
         //println!("{} {} {} {}", ret.view.m[0][3], ret.view.m[1][3], ret.view.m[2][3], ret.view.m[3][3]);
-        ret.projection.set_perspective(3.1415926 * 0.5, aspect, 1.0, 500.0);
+        ret.projection.set_perspective(FRAC_PI_2, aspect, 1.0, 500.0);
         //println!("{} {} {} {}", ret.projection.m[0][3], ret.projection.m[1][3], ret.projection.m[2][3], ret.projection.m[3][3]);
         ret.update();
         //println!("{} {} {} {}", ret.transform.m[0][3], ret.transform.m[1][3], ret.transform.m[2][3], ret.transform.m[3][3]);
@@ -1,9 +1,12 @@
 use crate::calc;
 
-use std::num;
 use crate::matrix_calc::Matrix4f;
 
-#[derive(Clone, Copy)]
+// w = 1.0 marks a point, w = 0.0 a direction. Adding a direction to a point
+// gives a point and subtracting two points gives a direction, so the
+// operators below work on all four components. Scaling does too: a weighted
+// sum of points is a point when the weights add up to 1.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct Vector4f {
     pub x: f32,
     pub y: f32,
@@ -11,6 +14,20 @@ pub struct Vector4f {
     pub w: f32,
 }
 
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Vector3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+// Screen-space positions and offsets, texture coordinate steps.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Vector2 {
+    pub x: f32,
+    pub y: f32,
+}
+
 impl Vector4f {
     pub fn new() -> Vector4f {
         Vector4f {
@@ -21,43 +38,84 @@ impl Vector4f {
         }
     }
 
+    pub fn point(x: f32, y: f32, z: f32) -> Vector4f {
+        Vector4f { x, y, z, w: 1.0 }
+    }
+
+    pub fn direction(x: f32, y: f32, z: f32) -> Vector4f {
+        Vector4f { x, y, z, w: 0.0 }
+    }
+
+    pub fn xyz(&self) -> Vector3 {
+        Vector3 { x: self.x, y: self.y, z: self.z }
+    }
+
+    pub fn xy(&self) -> Vector2 {
+        Vector2 { x: self.x, y: self.y }
+    }
+
+    // Homogeneous point back to w = 1.0, e.g. after a projection or its
+    // inverse.
+    pub fn perspective_divide(&self) -> Vector4f {
+        let inv = 1.0 / self.w;
+        Vector4f::point(self.x * inv, self.y * inv, self.z * inv)
+    }
+
     pub fn length(&self) -> f32 {
         let sq = self.x * self.x + self.y * self.y + self.z * self.z;
         return f32::sqrt(sq);
     }
 
+    pub fn dot(&self, y: Vector4f) -> f32 {
+        self.x * y.x + self.y * y.y + self.z * y.z
+    }
+
+    pub fn cross(&self, y: Vector4f) -> Vector4f {
+        Vector4f::direction(
+            self.y * y.z - self.z * y.y,
+            self.z * y.x - self.x * y.z,
+            self.x * y.y - self.y * y.x,
+        )
+    }
+
+    pub fn normalized(&self) -> Vector4f {
+        let mut ret = *self;
+        ret.normalize();
+        ret
+    }
+
+    pub fn lerp(&self, y: Vector4f, t: f32) -> Vector4f {
+        Vector4f {
+            x: calc::interp(self.x, y.x, t),
+            y: calc::interp(self.y, y.y, t),
+            z: calc::interp(self.z, y.z, t),
+            w: calc::interp(self.w, y.w, t),
+        }
+    }
+
+    // The out-parameter methods below predate the operators and keep their
+    // old behaviour of forcing w = 1.0.
     pub fn add(&mut self, x: Vector4f, y: Vector4f) {
-        self.x = x.x + y.x;
-        self.y = x.y + y.y;
-        self.z = x.z + y.z;
+        *self = x + y;
         self.w = 1.0;
     }
 
     pub fn sub(&mut self, x: Vector4f, y: Vector4f) {
-        self.x = x.x - y.x;
-        self.y = x.y - y.y;
-        self.z = x.z - y.z;
+        *self = x - y;
         self.w = 1.0;
     }
 
     pub fn dotproduct(&self, y: Vector4f) -> f32 {
-        return self.x * y.x + self.y * y.y + self.z * y.z;
+        self.dot(y)
     }
 
     pub fn crossproduct(&mut self, x: Vector4f, y: Vector4f) {
-        let mut m1 = x.y * y.z - x.z * y.y;
-        let mut m2 = x.z * y.x - x.x * y.z;
-        let mut m3 = x.x * y.y - x.y * y.x;
-        self.x = m1;
-        self.y = m2;
-        self.z = m3;
+        *self = x.cross(y);
         self.w = 1.0;
     }
 
     pub fn interp(&mut self, x1: Vector4f, x2: Vector4f, t: f32) {
-        self.x = calc::interp(x1.x, x2.x, t);
-        self.y = calc::interp(x1.y, x2.y, t);
-        self.z = calc::interp(x1.z, x2.z, t);
+        *self = x1.lerp(x2, t);
         self.w = 1.0;
     }
 
@@ -72,14 +130,298 @@ impl Vector4f {
     }
 
     pub fn matrix_apply(&mut self, x: Vector4f, m: Matrix4f) {
-        let X = x.x;
-        let Y = x.y;
-        let Z = x.z;
-        let W = x.w;
-        self.x = X * m.m[0][0] + Y * m.m[1][0] + Z * m.m[2][0] + W * m.m[3][0];
-        self.y = X * m.m[0][1] + Y * m.m[1][1] + Z * m.m[2][1] + W * m.m[3][1];
-        self.z = X * m.m[0][2] + Y * m.m[1][2] + Z * m.m[2][2] + W * m.m[3][2];
-        self.w = X * m.m[0][3] + Y * m.m[1][3] + Z * m.m[2][3] + W * m.m[3][3];
-        //println!("{} {} {} {}", self.x, self.y, self.z, self.w);
-    }
-}
\ No newline at end of file
+        *self = x * m;
+    }
+}
+
+impl std::ops::Add for Vector4f {
+    type Output = Vector4f;
+    fn add(self, y: Vector4f) -> Vector4f {
+        Vector4f { x: self.x + y.x, y: self.y + y.y, z: self.z + y.z, w: self.w + y.w }
+    }
+}
+
+impl std::ops::Sub for Vector4f {
+    type Output = Vector4f;
+    fn sub(self, y: Vector4f) -> Vector4f {
+        Vector4f { x: self.x - y.x, y: self.y - y.y, z: self.z - y.z, w: self.w - y.w }
+    }
+}
+
+impl std::ops::Mul<f32> for Vector4f {
+    type Output = Vector4f;
+    fn mul(self, f: f32) -> Vector4f {
+        Vector4f { x: self.x * f, y: self.y * f, z: self.z * f, w: self.w * f }
+    }
+}
+
+impl std::ops::Neg for Vector4f {
+    type Output = Vector4f;
+    fn neg(self) -> Vector4f {
+        Vector4f { x: -self.x, y: -self.y, z: -self.z, w: -self.w }
+    }
+}
+
+// Row vector times matrix, the convention used by every Matrix4f builder.
+impl std::ops::Mul<Matrix4f> for Vector4f {
+    type Output = Vector4f;
+    fn mul(self, m: Matrix4f) -> Vector4f {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+        Vector4f {
+            x: x * m.m[0][0] + y * m.m[1][0] + z * m.m[2][0] + w * m.m[3][0],
+            y: x * m.m[0][1] + y * m.m[1][1] + z * m.m[2][1] + w * m.m[3][1],
+            z: x * m.m[0][2] + y * m.m[1][2] + z * m.m[2][2] + w * m.m[3][2],
+            w: x * m.m[0][3] + y * m.m[1][3] + z * m.m[2][3] + w * m.m[3][3],
+        }
+    }
+}
+
+impl std::ops::Index<usize> for Vector4f {
+    type Output = f32;
+    fn index(&self, i: usize) -> &f32 {
+        match i {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            3 => &self.w,
+            _ => panic!("Vector4f index out of range: {}", i),
+        }
+    }
+}
+
+impl std::ops::IndexMut<usize> for Vector4f {
+    fn index_mut(&mut self, i: usize) -> &mut f32 {
+        match i {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            3 => &mut self.w,
+            _ => panic!("Vector4f index out of range: {}", i),
+        }
+    }
+}
+
+impl Vector3 {
+    pub fn new(x: f32, y: f32, z: f32) -> Vector3 {
+        Vector3 { x, y, z }
+    }
+
+    pub fn to_point(self) -> Vector4f {
+        Vector4f::point(self.x, self.y, self.z)
+    }
+
+    pub fn to_direction(self) -> Vector4f {
+        Vector4f::direction(self.x, self.y, self.z)
+    }
+
+    pub fn length(&self) -> f32 {
+        self.dot(*self).sqrt()
+    }
+
+    pub fn dot(&self, y: Vector3) -> f32 {
+        self.x * y.x + self.y * y.y + self.z * y.z
+    }
+
+    pub fn cross(&self, y: Vector3) -> Vector3 {
+        Vector3 {
+            x: self.y * y.z - self.z * y.y,
+            y: self.z * y.x - self.x * y.z,
+            z: self.x * y.y - self.y * y.x,
+        }
+    }
+
+    pub fn normalized(&self) -> Vector3 {
+        let len = self.length();
+        if len == 0.0 {
+            return *self;
+        }
+        *self * (1.0 / len)
+    }
+
+    pub fn lerp(&self, y: Vector3, t: f32) -> Vector3 {
+        Vector3 {
+            x: calc::interp(self.x, y.x, t),
+            y: calc::interp(self.y, y.y, t),
+            z: calc::interp(self.z, y.z, t),
+        }
+    }
+}
+
+impl std::ops::Add for Vector3 {
+    type Output = Vector3;
+    fn add(self, y: Vector3) -> Vector3 {
+        Vector3 { x: self.x + y.x, y: self.y + y.y, z: self.z + y.z }
+    }
+}
+
+impl std::ops::Sub for Vector3 {
+    type Output = Vector3;
+    fn sub(self, y: Vector3) -> Vector3 {
+        Vector3 { x: self.x - y.x, y: self.y - y.y, z: self.z - y.z }
+    }
+}
+
+impl std::ops::Mul<f32> for Vector3 {
+    type Output = Vector3;
+    fn mul(self, f: f32) -> Vector3 {
+        Vector3 { x: self.x * f, y: self.y * f, z: self.z * f }
+    }
+}
+
+impl std::ops::Neg for Vector3 {
+    type Output = Vector3;
+    fn neg(self) -> Vector3 {
+        Vector3 { x: -self.x, y: -self.y, z: -self.z }
+    }
+}
+
+impl std::ops::Index<usize> for Vector3 {
+    type Output = f32;
+    fn index(&self, i: usize) -> &f32 {
+        match i {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vector3 index out of range: {}", i),
+        }
+    }
+}
+
+impl std::ops::IndexMut<usize> for Vector3 {
+    fn index_mut(&mut self, i: usize) -> &mut f32 {
+        match i {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Vector3 index out of range: {}", i),
+        }
+    }
+}
+
+impl Vector2 {
+    pub fn new(x: f32, y: f32) -> Vector2 {
+        Vector2 { x, y }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.dot(*self).sqrt()
+    }
+
+    pub fn dot(&self, y: Vector2) -> f32 {
+        self.x * y.x + self.y * y.y
+    }
+
+    // z of the 3D cross product: twice the signed area of the triangle
+    // (0, self, y), positive when y is counter-clockwise from self.
+    pub fn cross(&self, y: Vector2) -> f32 {
+        self.x * y.y - self.y * y.x
+    }
+
+    pub fn normalized(&self) -> Vector2 {
+        let len = self.length();
+        if len == 0.0 {
+            return *self;
+        }
+        *self * (1.0 / len)
+    }
+}
+
+impl std::ops::Add for Vector2 {
+    type Output = Vector2;
+    fn add(self, y: Vector2) -> Vector2 {
+        Vector2 { x: self.x + y.x, y: self.y + y.y }
+    }
+}
+
+impl std::ops::Sub for Vector2 {
+    type Output = Vector2;
+    fn sub(self, y: Vector2) -> Vector2 {
+        Vector2 { x: self.x - y.x, y: self.y - y.y }
+    }
+}
+
+impl std::ops::Mul<f32> for Vector2 {
+    type Output = Vector2;
+    fn mul(self, f: f32) -> Vector2 {
+        Vector2 { x: self.x * f, y: self.y * f }
+    }
+}
+
+impl std::ops::Neg for Vector2 {
+    type Output = Vector2;
+    fn neg(self) -> Vector2 {
+        Vector2 { x: -self.x, y: -self.y }
+    }
+}
+
+impl std::ops::Index<usize> for Vector2 {
+    type Output = f32;
+    fn index(&self, i: usize) -> &f32 {
+        match i {
+            0 => &self.x,
+            1 => &self.y,
+            _ => panic!("Vector2 index out of range: {}", i),
+        }
+    }
+}
+
+impl std::ops::IndexMut<usize> for Vector2 {
+    fn index_mut(&mut self, i: usize) -> &mut f32 {
+        match i {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            _ => panic!("Vector2 index out of range: {}", i),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Vector2, Vector4f};
+    use crate::matrix_calc::Matrix4f;
+
+    #[test]
+    fn weighted_sum_of_points_is_a_point() {
+        let a = Vector4f::point(1., 2., 3.);
+        let b = Vector4f::point(5., -2., 7.);
+        let c = Vector4f::point(-3., 0., 1.);
+        assert_eq!(a * 0.5 + b * 0.5, Vector4f::point(3., 0., 5.));
+        assert_eq!((a * 0.25 + b * 0.25 + c * 0.5).w, 1.0);
+        assert_eq!(a.lerp(b, 0.25), a * 0.75 + b * 0.25);
+    }
+
+    #[test]
+    fn points_and_directions_combine() {
+        let p = Vector4f::point(1., 2., 3.);
+        let d = Vector4f::direction(0., 1., 0.);
+        assert_eq!((p + d * 2.0).w, 1.0);
+        assert_eq!((p - d * 2.0).w, 1.0);
+        assert_eq!((p - Vector4f::point(0., 0., 1.)).w, 0.0);
+        assert_eq!((d * 3.0).w, 0.0);
+        assert_eq!(-d, Vector4f::direction(0., -1., 0.));
+        assert_eq!(-p, Vector4f { x: -1., y: -2., z: -3., w: -1. });
+        assert_eq!(p + -p, Vector4f::new());
+    }
+
+    #[test]
+    fn perspective_divide_gives_a_point() {
+        let p = Vector4f { x: 2., y: -4., z: 1., w: 2. };
+        assert_eq!(p.perspective_divide(), Vector4f::point(1., -2., 0.5));
+        let m = Matrix4f::perspective(1.2, 4.0 / 3.0, 1.0, 500.0);
+        let q = Vector4f::point(0.5, -0.25, 10.) * m;
+        let back = (q.perspective_divide() * m.inverted().unwrap()).perspective_divide();
+        assert!((back - Vector4f::point(0.5, -0.25, 10.)).length() < 1e-4);
+        assert_eq!((q * 4.0).perspective_divide(), q.perspective_divide());
+    }
+
+    #[test]
+    fn vector2_cross_is_signed_area() {
+        let a = Vector2::new(2., 0.);
+        let b = Vector2::new(0., 3.);
+        assert_eq!(a.cross(b), 6.0);
+        assert_eq!(b.cross(a), -6.0);
+        assert_eq!(a.cross(a * 2.0), 0.0);
+        assert_eq!((a - b).length(), 13f32.sqrt());
+        assert_eq!(b.normalized(), Vector2::new(0., 1.));
+    }
+}
@@ -2,11 +2,14 @@ use crate::calc::interp;
 use crate::vector_calc::Vector4f;
 use crate::calc::{Texcoord, Color};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct Vertex {
     pub pos: Vector4f, // Point
     pub tc: Texcoord,
     pub color: Color,
+    pub normal: Vector4f,
+    pub tangent: Vector4f, // w is the bitangent sign
+    pub world: Vector4f, // world-space position, filled in by draw_primitive
     pub rhw: f32,
 }
 
@@ -18,6 +21,10 @@ pub struct Edge {
 }
 
 impl Vertex {
+    pub fn new() -> Vertex {
+        Vertex::default()
+    }
+
     pub fn rhw_init(&mut self) {
         let rhw = 1.0 / self.pos.w;
         self.rhw = rhw;
@@ -26,6 +33,9 @@ impl Vertex {
         self.color.r *= rhw;
         self.color.g *= rhw;
         self.color.b *= rhw;
+        self.normal = self.normal * rhw;
+        self.tangent = self.tangent * rhw;
+        self.world = self.world * rhw;
     }
 
     pub fn interp(&mut self, x1: Vertex, x2: Vertex, t: f32) {
@@ -35,6 +45,14 @@ impl Vertex {
         self.color.r = interp(x1.color.r, x2.color.r, t);
         self.color.g = interp(x1.color.g, x2.color.g, t);
         self.color.b = interp(x1.color.b, x2.color.b, t);
+        self.normal = x1.normal.lerp(x2.normal, t);
+        // the bitangent sign is not interpolated, it comes from the nearer end
+        self.tangent = x1.tangent.lerp(x2.tangent, t);
+        self.tangent.w = match t < 0.5 {
+            true => { x1.tangent.w }
+            false => { x2.tangent.w }
+        };
+        self.world = x1.world.lerp(x2.world, t);
         self.rhw = interp(x1.rhw, x2.rhw, t);
     }
 
@@ -49,6 +67,9 @@ impl Vertex {
         self.color.r = (x2.color.r - x1.color.r) * inv;
         self.color.g = (x2.color.g - x1.color.g) * inv;
         self.color.b = (x2.color.b - x1.color.b) * inv;
+        self.normal = (x2.normal - x1.normal) * inv;
+        self.tangent = Vector4f { w: 0.0, ..(x2.tangent - x1.tangent) * inv };
+        self.world = (x2.world - x1.world) * inv;
         self.rhw = (x2.rhw - x1.rhw) * inv;
     }
 
@@ -63,5 +84,8 @@ impl Vertex {
         self.color.r += x.color.r;
         self.color.g += x.color.g;
         self.color.b += x.color.b;
+        self.normal = self.normal + x.normal;
+        self.tangent = self.tangent + x.tangent;
+        self.world = self.world + x.world;
     }
 }
\ No newline at end of file